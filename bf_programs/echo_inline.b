@@ -0,0 +1,2 @@
+Echoes its input until EOI
+,[.,]!inline input
@@ -0,0 +1,103 @@
+//! A common interface over every way of running a BFIR program
+
+use std::io;
+
+use crate::{
+    bf_ir::BfIrScope,
+    compile_cranelift::{CodegenConfig, JitExit, JitProgram, JitState},
+    interpret::{Interpreter, RunError},
+    io_utils::ProgramIO,
+    opt::pass_manager::OptLevel,
+};
+
+/// The state of a program after a `Backend` finished running it
+#[derive(Debug)]
+pub struct RunResult {
+    pub tape: Box<[u8]>,
+    pub data_ptr: usize,
+    /// The I/O error which stopped the program early, if there was one
+    pub io_error: Option<io::Error>,
+    /// Whether the program was stopped early by using up its fuel limit, which is counted in `Backend::fuel_unit`
+    pub out_of_fuel: bool,
+    /// Whether the program was stopped early because it could never finish, such as a `Scan` with no zero cell in reach
+    pub never_halts: bool,
+}
+
+/// Something which can run a BFIR program to completion
+pub trait Backend {
+    /// A short name for this backend, for use in diagnostics
+    fn name(&self) -> &str;
+    /// What a unit of this backend's fuel limit is spent on, such as `"instructions"`.
+    /// The same limit only stops programs at the same point on backends with the same unit
+    fn fuel_unit(&self) -> &str;
+
+    /// Runs `program` until it finishes or stops early. Errors if the program can't be run at all,
+    /// such as when it can't be compiled
+    fn run(&mut self, program: BfIrScope, io: impl ProgramIO) -> anyhow::Result<RunResult>;
+}
+
+/// Runs programs with `interpret::Interpreter`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterpreterBackend {
+    /// The maximum number of instructions to execute, plus the steps of each `Scan`, or `None` if unlimited
+    pub fuel_limit: Option<u64>,
+    pub opt_level: OptLevel,
+}
+
+impl Backend for InterpreterBackend {
+    fn name(&self) -> &str {
+        "interpreter"
+    }
+    fn fuel_unit(&self) -> &str {
+        "instructions"
+    }
+
+    fn run(&mut self, program: BfIrScope, io: impl ProgramIO) -> anyhow::Result<RunResult> {
+        let mut interp = Interpreter::with_opt_level(program, io, self.opt_level);
+        interp.set_fuel(self.fuel_limit);
+        let err = interp.run().err();
+
+        Ok(RunResult {
+            tape: interp.tape().iter().map(|c| c.0).collect(),
+            data_ptr: interp.data_ptr(),
+            out_of_fuel: matches!(err, Some(RunError::OutOfFuel)),
+            never_halts: matches!(err, Some(RunError::NeverHalts)),
+            io_error: err.and_then(RunError::into_io_error),
+        })
+    }
+}
+
+/// Runs programs with `compile_cranelift::JitProgram`, compiling them for the host
+#[derive(Debug, Clone, Default)]
+pub struct JitBackend {
+    pub config: CodegenConfig,
+    /// The maximum number of loop iterations to start, including the steps of a `Scan`, or `None` if unlimited
+    pub fuel_limit: Option<u64>,
+}
+
+impl Backend for JitBackend {
+    fn name(&self) -> &str {
+        "jit"
+    }
+    fn fuel_unit(&self) -> &str {
+        "loop iterations"
+    }
+
+    fn run(&mut self, program: BfIrScope, mut io: impl ProgramIO) -> anyhow::Result<RunResult> {
+        let program = JitProgram::with_config(program, &self.config)?;
+
+        let mut state = JitState {
+            fuel: self.fuel_limit,
+            ..Default::default()
+        };
+        let res = program.run_with_state(&mut io, &mut state);
+
+        Ok(RunResult {
+            tape: state.tape,
+            data_ptr: state.data_ptr,
+            out_of_fuel: matches!(res, Ok(JitExit::OutOfFuel)),
+            never_halts: matches!(res, Ok(JitExit::NeverHalts)),
+            io_error: res.err(),
+        })
+    }
+}
@@ -1,17 +1,219 @@
-use std::{fmt::Debug, io::Read, ops::Deref, sync::Arc};
+use std::{
+    fmt::{Debug, Display},
+    io::{self, BufRead, BufReader, Read},
+    mem,
+    ops::Deref,
+    sync::Arc,
+};
 
+use serde::Serialize;
 use utf8_read::{Char, Reader};
 
-/// A cheaply clonable list of BF tokens
+/// The range of source bytes a token came from, along with the line and column where it starts
+///
+/// Tokens which were not parsed from source have a span of all `0`s
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize)]
+pub struct Span {
+    /// The offset of the first byte
+    pub start: usize,
+    /// The offset one past the last byte
+    pub end: usize,
+    /// The line of `start`, starting at `1`
+    pub line: usize,
+    /// The column of `start` in bytes, starting at `1`
+    pub col: usize,
+}
+
+impl Span {
+    /// The smallest span which covers both `self` and `other`
+    #[must_use]
+    pub fn join(self, other: Span) -> Span {
+        let (first, last) = if self.start <= other.start {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Span {
+            end: first.end.max(last.end),
+            ..first
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// A cheaply clonable list of BF tokens, along with the span of each token
 #[derive(Clone)]
 pub struct BfScope {
     toks: Arc<[BfTok]>,
+    spans: Arc<[Span]>,
+}
+
+impl BfScope {
+    /// Panics if `toks` and `spans` have different lengths
+    pub fn with_spans(toks: impl Into<Box<[BfTok]>>, spans: impl Into<Box<[Span]>>) -> Self {
+        let (toks, spans) = (toks.into(), spans.into());
+        assert_eq!(toks.len(), spans.len());
+        Self {
+            toks: Arc::from(toks),
+            spans: Arc::from(spans),
+        }
+    }
+    /// The span of each token
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+}
+
+impl Drop for BfScope {
+    fn drop(&mut self) {
+        drop_nested(&mut self.toks, |tok| match tok {
+            BfTok::Loop(inner) => Some(&mut inner.toks),
+            _ => None,
+        });
+    }
 }
 
 impl Debug for BfScope {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_list().entries(self.toks.iter()).finish()
+        debug_nested(f, self)
+    }
+}
+
+impl NestedScope for BfScope {
+    type Tok = BfTok;
+
+    fn toks(&self) -> &[BfTok] {
+        &self.toks
+    }
+    fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+    fn body(tok: &BfTok) -> Option<&Self> {
+        match tok {
+            BfTok::Loop(inner) => Some(inner),
+            _ => None,
+        }
+    }
+}
+
+/// A list of tokens with a span for each, where every loop token has a scope of its own, such as `BfScope`
+pub(crate) trait NestedScope {
+    type Tok;
+
+    fn toks(&self) -> &[Self::Tok];
+    fn spans(&self) -> &[Span];
+    /// The body of `tok`, if it's a loop
+    fn body(tok: &Self::Tok) -> Option<&Self>;
+}
+
+/// A step of `walk` through a scope and the loops nested inside of it
+pub(crate) enum Visit<'a, S: NestedScope> {
+    /// The token at `index` of `scope`, which is inside of `depth` loops. The body of a loop is visited right after it
+    Tok {
+        scope: &'a S,
+        index: usize,
+        tok: &'a S::Tok,
+        depth: usize,
+    },
+    /// The end of `body`, which belongs to the loop at `index` of `scope`
+    End {
+        scope: &'a S,
+        index: usize,
+        body: &'a S,
+        depth: usize,
+    },
+}
+
+/// Visits every token of `sc` and the loops inside of it in program order, without recursion
+pub(crate) fn walk<S: NestedScope>(sc: &S) -> impl Iterator<Item = Visit<'_, S>> {
+    // Each frame is `(scope, index of the next token)`
+    let mut frames = vec![(sc, 0)];
+    std::iter::from_fn(move || {
+        let depth = frames.len().checked_sub(1)?;
+        let (scope, i) = frames.last_mut()?;
+        let (scope, index) = (*scope, *i);
+        let Some(tok) = scope.toks().get(index) else {
+            frames.pop();
+            let &(parent, next) = frames.last()?;
+            return Some(Visit::End {
+                scope: parent,
+                index: next - 1,
+                body: scope,
+                depth: depth - 1,
+            });
+        };
+        *i += 1;
+        if let Some(body) = S::body(tok) {
+            frames.push((body, 0));
+        }
+        Some(Visit::Tok {
+            scope,
+            index,
+            tok,
+            depth,
+        })
+    })
+}
+
+/// Drops `toks` and the loops nested inside of it without recursion, where `body` gives the tokens of a loop
+pub(crate) fn drop_nested<T>(toks: &mut Arc<[T]>, body: impl Fn(&mut T) -> Option<&mut Arc<[T]>>) {
+    let mut bodies = vec![mem::replace(toks, Arc::new([]))];
+    while let Some(mut toks) = bodies.pop() {
+        // Shared tokens are left to whichever scope drops them last
+        if let Some(toks) = Arc::get_mut(&mut toks) {
+            let inner = toks.iter_mut().filter_map(&body);
+            bodies.extend(inner.map(|inner| mem::replace(inner, Arc::new([]))));
+        }
+    }
+}
+
+/// Writes `sc` like a derived `Debug` for a list.
+/// Loops are written as `Loop([..])`, and every other token with its own `Debug`
+///
+/// With `{:#?}`, each token is on its own line, indented by its depth
+pub(crate) fn debug_nested<S: NestedScope>(
+    f: &mut std::fmt::Formatter<'_>,
+    sc: &S,
+) -> std::fmt::Result
+where
+    S::Tok: Debug,
+{
+    let pretty = f.alternate();
+    // Writes the separator before the next token, or before the end of the list
+    let sep = |f: &mut std::fmt::Formatter<'_>, i: usize, depth: usize, end: bool| match pretty {
+        true if end && i == 0 => Ok(()),
+        true if end => write!(f, ",\n{:pad$}", "", pad = (depth - 1) * 4),
+        true if i == 0 => write!(f, "\n{:pad$}", "", pad = depth * 4),
+        true => write!(f, ",\n{:pad$}", "", pad = depth * 4),
+        false if i > 0 && !end => f.write_str(", "),
+        false => Ok(()),
+    };
+
+    f.write_str("[")?;
+    for visit in walk(sc) {
+        match visit {
+            Visit::Tok {
+                index, tok, depth, ..
+            } => {
+                sep(f, index, depth + 1, false)?;
+                match S::body(tok) {
+                    Some(_) => f.write_str("Loop([")?,
+                    None => write!(f, "{tok:?}")?,
+                }
+            }
+            Visit::End { body, depth, .. } => {
+                sep(f, body.toks().len(), depth + 2, true)?;
+                f.write_str("])")?;
+            }
+        }
     }
+    sep(f, sc.toks().len(), 1, true)?;
+    f.write_str("]")
 }
 
 impl<B> From<B> for BfScope
@@ -19,9 +221,9 @@ where
     B: Into<Box<[BfTok]>>,
 {
     fn from(value: B) -> Self {
-        Self {
-            toks: Arc::from(value.into()),
-        }
+        let toks: Box<[BfTok]> = value.into();
+        let spans = vec![Span::default(); toks.len()];
+        Self::with_spans(toks, spans)
     }
 }
 
@@ -40,7 +242,7 @@ impl Deref for BfScope {
 }
 
 /// A cheaply clonable BF token
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum BfTok {
     Read,
     Write,
@@ -48,51 +250,281 @@ pub enum BfTok {
     ValDec,
     PtrInc,
     PtrDec,
+    /// A `#`, which dumps the tape around the pointer. Only parsed with `BfParser::debug_commands`
+    Debug,
     Loop(BfScope),
 }
 
+impl Debug for BfTok {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BfTok::Read => f.write_str("Read"),
+            BfTok::Write => f.write_str("Write"),
+            BfTok::ValInc => f.write_str("ValInc"),
+            BfTok::ValDec => f.write_str("ValDec"),
+            BfTok::PtrInc => f.write_str("PtrInc"),
+            BfTok::PtrDec => f.write_str("PtrDec"),
+            BfTok::Debug => f.write_str("Debug"),
+            // `BfScope` writes nested loops itself, without recursing
+            BfTok::Loop(inner) => write!(f, "Loop({inner:?})"),
+        }
+    }
+}
+
+/// An error which stopped a `BfParser`
+#[derive(Debug)]
+pub enum ParseError {
+    /// The source could not be read
+    Io(io::Error),
+    /// The source could not be read, or was not valid UTF-8, when parsing with `BfParser::utf8`
+    Utf8(utf8_read::Error),
+    /// The `]` at `close` has no matching `[`
+    UnmatchedClose { close: Span },
+    /// The source (or the program before the input, with `BfParser::parse_with_input`) ended at `end`
+    /// while the `[` at `open` was still open. `end` is empty, with the offset of the end as both its `start` and `end`
+    UnclosedLoop { open: Span, end: Span },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "failed to read program source: {e}"),
+            ParseError::Utf8(e) => write!(f, "failed to read program source as UTF-8: {e}"),
+            ParseError::UnmatchedClose { close } => {
+                write!(f, "unmatched `]` at {close}")
+            }
+            ParseError::UnclosedLoop { open, end } => {
+                write!(
+                    f,
+                    "unclosed `[` at {open}, expected `]` before the end at {end}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            ParseError::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where a `BfParser` reads its source from
+enum Source<R: Read> {
+    /// Reads raw bytes, in any encoding
+    Bytes(BufReader<R>),
+    /// Reads UTF-8 chars, for dialects with multi-byte tokens
+    Utf8(Reader<BufReader<R>>),
+}
+
 /// A streaming BF parser, which wraps a `io::Read` instance
+///
+/// The source is read as bytes, so any bytes other than the BF commands are comments, regardless of encoding
 pub struct BfParser<R: Read> {
-    src: Reader<R>,
+    src: Source<R>,
+    /// The span of the next char
+    pos: Span,
+    lenient: bool,
+    debug_commands: bool,
+    /// Set by `parse_with_input`, to stop parsing at the first `!`
+    split_input: bool,
+    /// Whether the first `!` has been read
+    at_input: bool,
 }
 
 impl<R: Read> BfParser<R> {
     pub fn new(src: R) -> Self {
         Self {
-            src: Reader::new(src),
+            src: Source::Bytes(BufReader::new(src)),
+            pos: Span {
+                start: 0,
+                end: 0,
+                line: 1,
+                col: 1,
+            },
+            lenient: false,
+            debug_commands: false,
+            split_input: false,
+            at_input: false,
         }
     }
-    pub fn parse(mut self) -> anyhow::Result<BfScope> {
-        parse_stream(&mut self.src)
+    /// Accepts unbalanced brackets instead of returning an error.
+    /// An unmatched `]` ends the program, and loops which are still open at the end are closed
+    pub fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+    /// Parses `#` into `BfTok::Debug`, instead of treating it as a comment
+    pub fn debug_commands(mut self) -> Self {
+        self.debug_commands = true;
+        self
+    }
+    /// Reads the source as UTF-8 chars, returning `ParseError::Utf8` if it isn't valid UTF-8
+    pub fn utf8(self) -> Self {
+        Self {
+            src: match self.src {
+                Source::Bytes(r) => Source::Utf8(Reader::new(r)),
+                src => src,
+            },
+            ..self
+        }
+    }
+    pub fn parse(mut self) -> Result<BfScope, ParseError> {
+        self.parse_stream()
+    }
+    /// Parses the source up to the first `!`, and returns the bytes after it as the input of the program.
+    /// Without a `!`, the whole source is parsed and the input is empty
+    ///
+    /// This is the `program!input` format accepted by many BF runners
+    pub fn parse_with_input(mut self) -> Result<(BfScope, Vec<u8>), ParseError> {
+        self.split_input = true;
+        let scope = self.parse_stream()?;
+        let input = self.read_input()?;
+        Ok((scope, input))
     }
-}
 
-fn parse_stream<R: Read>(chars: &mut Reader<R>) -> anyhow::Result<BfScope> {
-    let mut toks = vec![];
-    loop {
-        let c = match chars.next_char()? {
-            Char::Eof | Char::NoData => break,
-            Char::Char(c) => c,
-        };
+    /// Skips to the first `!` if parsing stopped before it, then reads the rest of the source
+    fn read_input(&mut self) -> Result<Vec<u8>, ParseError> {
+        while !self.at_input {
+            match self.next_char()? {
+                Some((b'!', _)) => self.at_input = true,
+                Some(_) => (),
+                None => return Ok(vec![]),
+            }
+        }
 
-        match c {
-            '+' => toks.push(BfTok::ValInc),
-            '-' => toks.push(BfTok::ValDec),
-            '>' => toks.push(BfTok::PtrInc),
-            '<' => toks.push(BfTok::PtrDec),
-            '.' => toks.push(BfTok::Write),
-            ',' => toks.push(BfTok::Read),
-            // We don't need to keep track of depth for loops
-            '[' => {
-                let body = parse_stream(chars)?;
-                toks.push(BfTok::Loop(body))
+        let mut input = vec![];
+        match &mut self.src {
+            Source::Bytes(r) => {
+                r.read_to_end(&mut input).map_err(ParseError::Io)?;
             }
-            ']' => {
-                break;
+            Source::Utf8(r) => loop {
+                match r.next_char().map_err(ParseError::Utf8)? {
+                    Char::Eof | Char::NoData => break,
+                    Char::Char(c) => input.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            },
+        }
+        Ok(input)
+    }
+
+    /// Reads the next char, along with its span. Chars which are not ASCII are read as `0xFF`
+    fn next_char(&mut self) -> Result<Option<(u8, Span)>, ParseError> {
+        let (c, len) = match &mut self.src {
+            Source::Bytes(r) => {
+                let buf = loop {
+                    match r.fill_buf() {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        res => break res.map_err(ParseError::Io)?,
+                    }
+                };
+                let Some(&b) = buf.first() else {
+                    return Ok(None);
+                };
+                r.consume(1);
+                (b, 1)
             }
-            _ => (),
+            Source::Utf8(r) => match r.next_char().map_err(ParseError::Utf8)? {
+                Char::Eof | Char::NoData => return Ok(None),
+                Char::Char(c) if c.is_ascii() => (c as u8, 1),
+                Char::Char(c) => (0xFF, c.len_utf8()),
+            },
+        };
+
+        let span = Span {
+            end: self.pos.start + len,
+            ..self.pos
+        };
+        self.pos.start += len;
+        if c == b'\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += len;
         }
+
+        Ok(Some((c, span)))
     }
 
-    Ok(toks.into())
+    /// Parses the whole source, with an explicit stack of the loops which are still open
+    fn parse_stream(&mut self) -> Result<BfScope, ParseError> {
+        // The tokens and spans of the innermost scope being parsed
+        let mut toks = vec![];
+        let mut spans = vec![];
+        // For each open loop, the tokens and spans of its enclosing scope along with the span of its `[`
+        let mut open_loops: Vec<(Vec<BfTok>, Vec<Span>, Span)> = vec![];
+        // Where the program ends, which is before the input when splitting it off
+        let mut end = None;
+
+        while let Some((c, span)) = self.next_char()? {
+            let tok = match c {
+                b'+' => BfTok::ValInc,
+                b'-' => BfTok::ValDec,
+                b'>' => BfTok::PtrInc,
+                b'<' => BfTok::PtrDec,
+                b'.' => BfTok::Write,
+                b',' => BfTok::Read,
+                b'#' if self.debug_commands => BfTok::Debug,
+                b'!' if self.split_input => {
+                    self.at_input = true;
+                    end = Some(span);
+                    break;
+                }
+                b'[' => {
+                    open_loops.push((mem::take(&mut toks), mem::take(&mut spans), span));
+                    continue;
+                }
+                b']' => {
+                    let Some((outer_toks, outer_spans, open)) = open_loops.pop() else {
+                        if self.lenient {
+                            break;
+                        }
+                        return Err(ParseError::UnmatchedClose { close: span });
+                    };
+                    let body = BfScope::with_spans(
+                        mem::replace(&mut toks, outer_toks),
+                        mem::replace(&mut spans, outer_spans),
+                    );
+                    toks.push(BfTok::Loop(body));
+                    // Covers everything up to and including the closing bracket
+                    spans.push(Span {
+                        end: span.end,
+                        ..open
+                    });
+                    continue;
+                }
+                _ => continue,
+            };
+            toks.push(tok);
+            spans.push(span);
+        }
+
+        // An empty span where the program ends
+        let end = end.unwrap_or(self.pos);
+        let end = Span {
+            end: end.start,
+            ..end
+        };
+        if let (Some(&(_, _, open)), false) = (open_loops.last(), self.lenient) {
+            return Err(ParseError::UnclosedLoop { open, end });
+        }
+        // Closes the remaining loops, for lenient parsing
+        while let Some((outer_toks, outer_spans, open)) = open_loops.pop() {
+            let body = BfScope::with_spans(
+                mem::replace(&mut toks, outer_toks),
+                mem::replace(&mut spans, outer_spans),
+            );
+            toks.push(BfTok::Loop(body));
+            spans.push(Span {
+                end: end.start,
+                ..open
+            });
+        }
+
+        Ok(BfScope::with_spans(toks, spans))
+    }
 }
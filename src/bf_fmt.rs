@@ -0,0 +1,288 @@
+//! Turns BF and BFIR programs back into BF source, either minified or pretty-printed
+
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    mem,
+    num::Wrapping,
+    ops::Range,
+};
+
+use crate::{
+    bf::{walk, BfScope, BfTok, Visit},
+    bf_ir::{Adds, BfIrScope, BfIrTok},
+};
+
+/// How `BfSource` lays out the commands of a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmtStyle {
+    /// Every command on a single line, without comments or whitespace
+    Minified,
+    /// Loops which don't fit on one line are split, with the body indented by `indent` spaces per depth.
+    /// Lines are wrapped at `width` columns, unless the indentation alone is wider
+    Pretty { indent: usize, width: usize },
+}
+
+impl Default for FmtStyle {
+    fn default() -> Self {
+        FmtStyle::Pretty {
+            indent: 2,
+            width: 80,
+        }
+    }
+}
+
+/// BF source code for a program, which is written out by `write_to` or `Display`
+#[derive(Debug, Clone)]
+pub struct BfSource {
+    /// Only the eight BF commands and `#`, with balanced brackets
+    cmds: Vec<u8>,
+    /// The comments written by `FmtStyle::Pretty`, as `(index of the command they come before, text)` in order
+    comments: Vec<(usize, Vec<u8>)>,
+    style: FmtStyle,
+}
+
+impl BfSource {
+    /// Writes the commands of `sc`, without any comments
+    pub fn from_bf(sc: &BfScope, style: FmtStyle) -> Self {
+        let (cmds, _) = bf_commands(sc);
+        Self {
+            cmds,
+            comments: vec![],
+            style,
+        }
+    }
+
+    /// Like `from_bf`, but also keeps the comments in `src`, which `sc` was parsed from.
+    /// `src` should end where the program does, before any input after a `!`
+    pub fn with_comments(sc: &BfScope, src: &[u8], style: FmtStyle) -> Self {
+        let (cmds, ranges) = bf_commands(sc);
+
+        // Everything between two commands is a comment, as is everything after the last one
+        let mut comments = vec![];
+        let mut prev_end = 0;
+        let end = src.len()..src.len();
+        for (i, range) in ranges.iter().chain([&end]).enumerate() {
+            if let Some(text) = src.get(prev_end..range.start) {
+                if !text.trim_ascii().is_empty() {
+                    comments.push((i, text.to_vec()));
+                }
+            }
+            prev_end = prev_end.max(range.end);
+        }
+
+        Self {
+            cmds,
+            comments,
+            style,
+        }
+    }
+
+    /// Lowers every BFIR token back into BF commands with the same behavior
+    pub fn from_ir(sc: &BfIrScope, style: FmtStyle) -> Self {
+        let mut cmds = vec![];
+        for visit in walk(sc) {
+            let tok = match visit {
+                Visit::Tok { tok, .. } => tok,
+                Visit::End { .. } => {
+                    cmds.push(b']');
+                    continue;
+                }
+            };
+
+            match tok {
+                BfIrTok::Modify { adds, ptr_delta } => lower_modify(adds, *ptr_delta, &mut cmds),
+                BfIrTok::Set(val) => {
+                    cmds.extend(b"[-]");
+                    lower_modify(&Adds::from_iter([(0, Wrapping(*val as i8))]), 0, &mut cmds);
+                }
+                BfIrTok::MulAdd(factors) => {
+                    let mut body = factors.clone();
+                    body.add(0, Wrapping(-1));
+                    cmds.push(b'[');
+                    lower_modify(&body, 0, &mut cmds);
+                    cmds.push(b']');
+                }
+                BfIrTok::Scan(stride) => {
+                    cmds.push(b'[');
+                    lower_modify(&Adds::new(), *stride, &mut cmds);
+                    cmds.push(b']');
+                }
+                BfIrTok::Read => cmds.push(b','),
+                BfIrTok::Write => cmds.push(b'.'),
+                BfIrTok::Debug => cmds.push(b'#'),
+                BfIrTok::Loop(_) => cmds.push(b'['),
+            }
+        }
+
+        Self {
+            cmds,
+            comments: vec![],
+            style,
+        }
+    }
+
+    /// Only the BF commands, without any whitespace
+    pub fn commands(&self) -> &[u8] {
+        &self.cmds
+    }
+}
+
+/// The commands of `sc`, along with the range of source bytes each one came from
+fn bf_commands(sc: &BfScope) -> (Vec<u8>, Vec<Range<usize>>) {
+    let mut cmds = vec![];
+    let mut ranges = vec![];
+    for visit in walk(sc) {
+        let (cmd, range) = match visit {
+            Visit::Tok {
+                scope, index, tok, ..
+            } => {
+                let span = scope.spans()[index];
+                match tok {
+                    BfTok::Read => (b',', span.start..span.end),
+                    BfTok::Write => (b'.', span.start..span.end),
+                    BfTok::ValInc => (b'+', span.start..span.end),
+                    BfTok::ValDec => (b'-', span.start..span.end),
+                    BfTok::PtrInc => (b'>', span.start..span.end),
+                    BfTok::PtrDec => (b'<', span.start..span.end),
+                    BfTok::Debug => (b'#', span.start..span.end),
+                    BfTok::Loop(_) => (b'[', span.start..span.start + 1),
+                }
+            }
+            Visit::End { scope, index, .. } => {
+                // A loop's span ends with its `]`
+                let end = scope.spans()[index].end;
+                (b']', end.saturating_sub(1)..end)
+            }
+        };
+        cmds.push(cmd);
+        ranges.push(range);
+    }
+    (cmds, ranges)
+}
+
+/// Emits the commands for `adds` followed by a move to `ptr_delta`,
+/// visiting the offsets in whichever direction needs the fewest pointer moves
+fn lower_modify(adds: &Adds, ptr_delta: isize, cmds: &mut Vec<u8>) {
+    let mut ptr = 0;
+    let mut move_to = |cmds: &mut Vec<u8>, target: isize| {
+        let c = if target > ptr { b'>' } else { b'<' };
+        cmds.extend(std::iter::repeat_n(c, target.abs_diff(ptr)));
+        ptr = target;
+    };
+
+    let (Some(&(min, _)), Some(&(max, _))) = (adds.first(), adds.last()) else {
+        move_to(cmds, ptr_delta);
+        return;
+    };
+    let ascending_cost = min.abs_diff(0) + max.abs_diff(ptr_delta);
+    let descending_cost = max.abs_diff(0) + min.abs_diff(ptr_delta);
+
+    let mut add_at = |cmds: &mut Vec<u8>, &(offset, Wrapping(delta)): &(isize, Wrapping<i8>)| {
+        move_to(cmds, offset);
+        let c = if delta > 0 { b'+' } else { b'-' };
+        cmds.extend(std::iter::repeat_n(c, usize::from(delta.unsigned_abs())));
+    };
+    if ascending_cost <= descending_cost {
+        adds.iter().for_each(|add| add_at(cmds, add));
+    } else {
+        adds.iter().rev().for_each(|add| add_at(cmds, add));
+    }
+    move_to(cmds, ptr_delta);
+}
+
+impl BfSource {
+    /// Writes out the source, keeping comments byte for byte in whatever encoding they were in
+    pub fn write_to(&self, mut w: impl io::Write) -> io::Result<()> {
+        let mut first_line = true;
+        self.layout(|pad, text| {
+            if !mem::take(&mut first_line) {
+                w.write_all(b"\n")?;
+            }
+            write!(w, "{:pad$}", "")?;
+            w.write_all(text)
+        })
+    }
+
+    /// Lays out the source as lines, calling `line(padding, text)` for each one
+    fn layout<E>(&self, mut line: impl FnMut(usize, &[u8]) -> Result<(), E>) -> Result<(), E> {
+        let (indent, width) = match self.style {
+            FmtStyle::Minified => return line(0, &self.cmds),
+            FmtStyle::Pretty { indent, width } => (indent, width),
+        };
+
+        // The index of the `]` matching each `[`
+        let mut closes = vec![0; self.cmds.len()];
+        let mut opens = vec![];
+        for (i, &c) in self.cmds.iter().enumerate() {
+            match c {
+                b'[' => opens.push(i),
+                b']' => closes[opens.pop().unwrap()] = i,
+                _ => (),
+            }
+        }
+
+        let mut comments = self.comments.iter().peekable();
+        let mut depth = 0;
+        let mut i = 0;
+        loop {
+            while let Some((_, text)) = comments.next_if(|(at, _)| *at == i) {
+                for text_line in text.split(|&c| c == b'\n') {
+                    let text_line = text_line.trim_ascii();
+                    if !text_line.is_empty() {
+                        line(depth * indent, text_line)?;
+                    }
+                }
+            }
+            let Some(&c) = self.cmds.get(i) else {
+                break;
+            };
+            // Commands are only joined into a line up to the next comment
+            let next_comment = comments.peek().map_or(self.cmds.len(), |(at, _)| *at);
+            // At least one command fits on every line
+            let room = width.saturating_sub(depth * indent).max(1);
+            match c {
+                b'[' => {
+                    let end = closes[i];
+                    if end - i < room && end < next_comment {
+                        line(depth * indent, &self.cmds[i..=end])?;
+                        i = end + 1;
+                    } else {
+                        line(depth * indent, b"[")?;
+                        depth += 1;
+                        i += 1;
+                    }
+                }
+                b']' => {
+                    depth -= 1;
+                    line(depth * indent, b"]")?;
+                    i += 1;
+                }
+                _ => {
+                    let run_len = self.cmds[i..next_comment]
+                        .iter()
+                        .position(|c| matches!(c, b'[' | b']'))
+                        .unwrap_or(next_comment - i);
+                    for chunk in self.cmds[i..i + run_len].chunks(room) {
+                        line(depth * indent, chunk)?;
+                    }
+                    i += run_len;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Comments which aren't UTF-8 are written lossily, so sources with comments should be written with `BfSource::write_to`
+impl Display for BfSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first_line = true;
+        self.layout(|pad, text| {
+            if !mem::take(&mut first_line) {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:pad$}{}", "", String::from_utf8_lossy(text))
+        })
+    }
+}
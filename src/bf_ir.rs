@@ -1,10 +1,12 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::fmt::{Debug, Display};
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::sync::Arc;
 
-use crate::bf::{BfParser, BfScope};
+use smallvec::SmallVec;
+
+use crate::bf::{debug_nested, drop_nested, walk, BfParser, BfScope, NestedScope, Span, Visit};
 
 /// The maximum number of cells which is supported for a program.
 /// Accessing any cell beyond this index results in undefined behavior
@@ -12,13 +14,27 @@ pub const MAX_CELL_COUNT: usize = 1024 * 1024;
 
 // pub struct InsOffset(pub isize);
 
-/// A cheaply clonable list of BFIR tokens
+/// A cheaply clonable list of BFIR tokens, along with the span of each token
 #[derive(Clone)]
 pub struct BfIrScope {
     toks: Arc<[BfIrTok]>,
+    spans: Arc<[Span]>,
 }
 
 impl BfIrScope {
+    /// Panics if `toks` and `spans` have different lengths
+    pub fn with_spans(toks: impl Into<Box<[BfIrTok]>>, spans: impl Into<Box<[Span]>>) -> Self {
+        let (toks, spans) = (toks.into(), spans.into());
+        assert_eq!(toks.len(), spans.len());
+        Self {
+            toks: Arc::from(toks),
+            spans: Arc::from(spans),
+        }
+    }
+    /// The span of each token. A `Modify` covers every BF token which was merged into it
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
     pub fn parse(b: impl std::io::Read) -> anyhow::Result<Self> {
         Ok(Self::from_bf(BfParser::new(b).parse()?))
     }
@@ -27,58 +43,86 @@ impl BfIrScope {
     }
     pub fn from_bf(bf: BfScope) -> Self {
         use crate::bf::BfTok;
-        let toks = bf.to_vec();
-        Self {
-            toks: toks
-                .into_iter()
-                .fold(vec![], |mut list, tok| {
-                    if let BfTok::Loop(s) = tok {
-                        list.push(BfIrTok::Loop(Self::from_bf(s)));
-                        return list;
-                    };
-                    if let BfTok::Read = tok {
-                        list.push(BfIrTok::Read);
-                        return list;
-                    };
-                    if let BfTok::Write = tok {
-                        list.push(BfIrTok::Write);
-                        return list;
-                    };
 
-                    match list.last() {
-                        Some(BfIrTok::Modify { .. }) => (),
-                        _ => list.push(BfIrTok::Modify {
-                            adds: HashMap::new(),
-                            ptr_delta: 0,
-                        }),
+        Self::rebuild(bf, |bf, i, out, _: &mut ()| {
+            let span = bf.spans()[i];
+            match &bf[i] {
+                BfTok::Loop(inner) => return Some(inner.clone()),
+                BfTok::Read => out.push(BfIrTok::Read, span),
+                BfTok::Write => out.push(BfIrTok::Write, span),
+                BfTok::Debug => out.push(BfIrTok::Debug, span),
+                tok @ (BfTok::ValInc | BfTok::ValDec | BfTok::PtrInc | BfTok::PtrDec) => {
+                    match out.toks.last() {
+                        Some(BfIrTok::Modify { .. }) => {
+                            let last = out.spans.last_mut().unwrap();
+                            *last = last.join(span);
+                        }
+                        _ => out.push(
+                            BfIrTok::Modify {
+                                adds: Adds::new(),
+                                ptr_delta: 0,
+                            },
+                            span,
+                        ),
                     }
 
-                    let Some(BfIrTok::Modify { adds, ptr_delta }) = list.last_mut() else {
+                    let Some(BfIrTok::Modify { adds, ptr_delta }) = out.toks.last_mut() else {
                         unreachable!()
                     };
 
                     match tok {
-                        BfTok::ValInc => *adds.entry(*ptr_delta).or_default() += 1,
-                        BfTok::ValDec => *adds.entry(*ptr_delta).or_default() -= 1,
+                        BfTok::ValInc => adds.add(*ptr_delta, Wrapping(1)),
+                        BfTok::ValDec => adds.add(*ptr_delta, Wrapping(-1)),
                         BfTok::PtrInc => *ptr_delta += 1,
                         BfTok::PtrDec => *ptr_delta -= 1,
 
-                        BfTok::Read | BfTok::Write | BfTok::Loop(..) => unreachable!(),
+                        BfTok::Read | BfTok::Write | BfTok::Debug | BfTok::Loop(..) => {
+                            unreachable!()
+                        }
                     };
-                    list
-                })
-                .into(),
+                }
+            }
+            None
+        })
+    }
+    /// Builds a new scope out of `sc` and the loops nested inside of it, one scope at a time
+    ///
+    /// `step(scope, index, out, state)` is called on each token of a scope in order, where `state` starts out as the default for every scope.
+    /// It pushes whatever replaces the token onto `out`, or returns the body of a loop to rebuild next,
+    /// which is then pushed as a loop with the span of the token. `scope` may be edited from `index` onwards
+    pub(crate) fn rebuild<S: NestedScope, St: Default>(
+        sc: S,
+        mut step: impl FnMut(&mut S, usize, &mut ScopeBuilder, &mut St) -> Option<S>,
+    ) -> Self {
+        // Each frame is `(scope, index of the next token, rebuilt tokens, state)`
+        let mut frames = vec![(sc, 0, ScopeBuilder::default(), St::default())];
+        loop {
+            let (sc, i, out, state) = frames.last_mut().unwrap();
+            if *i < sc.toks().len() {
+                *i += 1;
+                if let Some(body) = step(sc, *i - 1, out, state) {
+                    frames.push((body, 0, ScopeBuilder::default(), St::default()));
+                }
+                continue;
+            }
+
+            let (_, _, out, _) = frames.pop().unwrap();
+            let body = Self::with_spans(out.toks, out.spans);
+            let Some((parent, i, out, _)) = frames.last_mut() else {
+                return body;
+            };
+            out.push(BfIrTok::Loop(body), parent.spans()[*i - 1]);
         }
     }
+    /// Edits the tokens of the scope and their spans, which must be kept the same length
     #[must_use]
-    pub fn modify(self, f: impl FnOnce(&mut Vec<BfIrTok>)) -> Self {
+    pub fn modify(self, f: impl FnOnce(&mut Vec<BfIrTok>, &mut Vec<Span>)) -> Self {
         // For some reason, this measured consistently faster than `self.toks.to_vec()`,
         // about %10 optimization time improvement
         let mut toks = Vec::from_iter(self.toks.iter().cloned());
-        f(&mut toks);
-        Self {
-            toks: Arc::from(toks),
-        }
+        let mut spans = self.spans.to_vec();
+        f(&mut toks, &mut spans);
+        Self::with_spans(toks, spans)
     }
     /// Gets the number of tokens in
     pub fn len_flat(&self) -> usize {
@@ -88,40 +132,76 @@ impl BfIrScope {
     ///
     /// Each loop token is `1 + len(loop_body)`
     pub fn len(&self) -> usize {
-        let mut i = 0;
-        for tok in self.toks.iter() {
-            match tok {
-                BfIrTok::Loop(sc) => i += sc.len(),
-                _ => (),
-            }
+        self.subscopes().map(|sc| sc.len_flat()).sum()
+    }
 
-            i += 1;
+    /// Returns the subscope of `self` (including `self`) with the largest `len_flat`.
+    /// Ties go to the first in program order
+    pub fn largest_subscope(&self) -> BfIrScope {
+        let mut largest = self;
+        for sc in self.subscopes() {
+            if sc.len_flat() > largest.len_flat() {
+                largest = sc;
+            }
         }
+        largest.clone()
+    }
 
-        i
+    /// Iterates over `self` and the bodies of all the loops inside of it, in program order
+    pub fn subscopes(&self) -> impl Iterator<Item = &BfIrScope> {
+        std::iter::once(self).chain(walk(self).filter_map(|visit| match visit {
+            Visit::Tok {
+                tok: BfIrTok::Loop(inner),
+                ..
+            } => Some(inner),
+            _ => None,
+        }))
     }
+}
 
-    /// Returns the subscope of `self` (including `self`) with the largest `len_flat`
-    pub fn largest_subscope(&self) -> BfIrScope {
-        let mut largest = self.clone();
-        for tok in &self[..] {
-            match tok {
-                BfIrTok::Loop(inner) => {
-                    let inner = inner.largest_subscope();
-                    if inner.len_flat() > largest.len_flat() {
-                        largest = inner;
-                    }
-                }
-                _ => (),
-            }
+/// The tokens and spans of a scope being built by `BfIrScope::rebuild`
+#[derive(Default)]
+pub(crate) struct ScopeBuilder {
+    pub toks: Vec<BfIrTok>,
+    pub spans: Vec<Span>,
+}
+
+impl ScopeBuilder {
+    pub fn push(&mut self, tok: BfIrTok, span: Span) {
+        self.toks.push(tok);
+        self.spans.push(span);
+    }
+}
+
+impl Drop for BfIrScope {
+    fn drop(&mut self) {
+        drop_nested(&mut self.toks, |tok| match tok {
+            BfIrTok::Loop(inner) => Some(&mut inner.toks),
+            _ => None,
+        });
+    }
+}
+
+impl NestedScope for BfIrScope {
+    type Tok = BfIrTok;
+
+    fn toks(&self) -> &[BfIrTok] {
+        &self.toks
+    }
+    fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+    fn body(tok: &BfIrTok) -> Option<&Self> {
+        match tok {
+            BfIrTok::Loop(inner) => Some(inner),
+            _ => None,
         }
-        largest
     }
 }
 
 impl Debug for BfIrScope {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_list().entries(self.toks.iter()).finish()
+        debug_nested(f, self)
     }
 }
 
@@ -129,21 +209,25 @@ impl Display for BfIrScope {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let delta_indent = f.width().unwrap_or(2);
         let indent = f.precision().map(|p| p + delta_indent).unwrap_or(0);
-        let pad = " ".repeat(indent);
 
-        let s = self
-            .toks
-            .iter()
-            .map(|tok| {
-                format!(
-                    "{pad}{tok:width$.precision$}",
-                    width = delta_indent,
-                    precision = indent
-                )
-            })
-            .collect::<Vec<_>>()
-            .join("\n");
-        write!(f, "{s}")
+        // Each token is on its own line, and loop bodies are indented by `delta_indent` more than their loop.
+        // The first line of a loop body follows the padding of the loop itself
+        for visit in walk(self) {
+            let Visit::Tok {
+                index, tok, depth, ..
+            } = visit
+            else {
+                continue;
+            };
+            if index > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:pad$}", "", pad = indent + depth * delta_indent)?;
+            if !matches!(tok, BfIrTok::Loop(_)) {
+                write!(f, "{tok}")?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -152,9 +236,9 @@ where
     B: Into<Box<[BfIrTok]>>,
 {
     fn from(value: B) -> Self {
-        Self {
-            toks: Arc::from(value.into()),
-        }
+        let toks: Box<[BfIrTok]> = value.into();
+        let spans = vec![Span::default(); toks.len()];
+        Self::with_spans(toks, spans)
     }
 }
 
@@ -173,19 +257,108 @@ impl Deref for BfIrScope {
 }
 
 /// A cheaply clonable BFIR token
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum BfIrTok {
     /// A set of modifications to do onto the data buffer
     Modify {
-        adds: HashMap<isize, Wrapping<i8>>,
+        adds: Adds,
         /// The overall change to the `data_ptr` after all the adds are computed
         ptr_delta: isize,
     },
+    /// Sets the current cell, such as `[-]` setting it to `0`
+    Set(u8),
+    /// Adds the current cell times the factor at each offset to that cell, then sets the current cell to `0`,
+    /// such as `[->+>++<<]` with the factors `+1@1 +2@2`
+    MulAdd(Adds),
+    /// Moves the pointer by the stride until it's on a zero cell, such as `[>]` with a stride of `1`
+    Scan(isize),
     Write,
     Read,
+    /// Dumps the tape around the pointer when the interpreter executes it. Other backends ignore it
+    Debug,
     Loop(BfIrScope),
 }
 
+impl Debug for BfIrTok {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BfIrTok::Modify { adds, ptr_delta } => f
+                .debug_struct("Modify")
+                .field("adds", adds)
+                .field("ptr_delta", ptr_delta)
+                .finish(),
+            BfIrTok::Set(val) => f.debug_tuple("Set").field(val).finish(),
+            BfIrTok::MulAdd(factors) => f.debug_tuple("MulAdd").field(factors).finish(),
+            BfIrTok::Scan(stride) => f.debug_tuple("Scan").field(stride).finish(),
+            BfIrTok::Write => f.write_str("Write"),
+            BfIrTok::Read => f.write_str("Read"),
+            BfIrTok::Debug => f.write_str("Debug"),
+            // `BfIrScope` writes nested loops itself, without recursing
+            BfIrTok::Loop(inner) => write!(f, "Loop({inner:?})"),
+        }
+    }
+}
+
+/// The changes a `BfIrTok::Modify` makes to each cell, as `(offset, delta)` pairs sorted by offset
+///
+/// Each offset appears at most once, and never with a delta of `0`,
+/// so equal changes always compare, hash, display and compile the same
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Adds(SmallVec<[(isize, Wrapping<i8>); 4]>);
+
+impl Adds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds `delta` to the change at `offset`
+    pub fn add(&mut self, offset: isize, delta: Wrapping<i8>) {
+        match self.0.binary_search_by_key(&offset, |&(o, _)| o) {
+            Ok(i) => {
+                self.0[i].1 += delta;
+                if self.0[i].1 .0 == 0 {
+                    self.0.remove(i);
+                }
+            }
+            Err(_) if delta.0 == 0 => (),
+            Err(i) => self.0.insert(i, (offset, delta)),
+        }
+    }
+    /// The change at `offset`, which is `0` if there is none
+    pub fn get(&self, offset: isize) -> Wrapping<i8> {
+        self.0
+            .binary_search_by_key(&offset, |&(o, _)| o)
+            .map_or(Wrapping(0), |i| self.0[i].1)
+    }
+}
+
+impl Deref for Adds {
+    type Target = [(isize, Wrapping<i8>)];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a> IntoIterator for &'a Adds {
+    type Item = &'a (isize, Wrapping<i8>);
+    type IntoIter = std::slice::Iter<'a, (isize, Wrapping<i8>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl FromIterator<(isize, Wrapping<i8>)> for Adds {
+    /// Sums the deltas of repeated offsets
+    fn from_iter<T: IntoIterator<Item = (isize, Wrapping<i8>)>>(iter: T) -> Self {
+        let mut adds = Self::new();
+        for (offset, delta) in iter {
+            adds.add(offset, delta);
+        }
+        adds
+    }
+}
+
 impl Display for BfIrTok {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -197,8 +370,20 @@ impl Display for BfIrTok {
                     .collect::<Vec<_>>()
                     .join(" ")
             )?,
+            BfIrTok::Set(val) => write!(f, "={val}")?,
+            BfIrTok::MulAdd(factors) => write!(
+                f,
+                "*[{}]",
+                factors
+                    .iter()
+                    .map(|(offset, factor)| format!("{factor:+}@{offset}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )?,
+            BfIrTok::Scan(stride) => write!(f, "[>{stride}]")?,
             BfIrTok::Read => f.write_str(",")?,
             BfIrTok::Write => f.write_str(".")?,
+            BfIrTok::Debug => f.write_str("#")?,
             BfIrTok::Loop(lp) => f.write_fmt(format_args!(
                 "{lp:w$.p$}",
                 w = f.width().unwrap_or(2),
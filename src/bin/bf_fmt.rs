@@ -0,0 +1,133 @@
+//! Formats BF source files
+//!
+//! Usage: `bf_fmt [--minify] [--indent N] [--width N] [--debug-commands] [--inline-input] [--write] [FILE...]`
+//!
+//! Reads stdin when no files are given. With `--write`, files are formatted in place instead of printed.
+//! Comments are kept unless minifying. With `--debug-commands`, `#` is a command, so it's kept when minifying too.
+//! With `--inline-input`, everything after the first `!` is input for the program, which is written back unchanged
+
+use std::{
+    fs,
+    io::{stdin, stdout, Read, Write},
+    process::ExitCode,
+};
+
+use bf_cranelift::{
+    bf::BfParser,
+    bf_fmt::{BfSource, FmtStyle},
+};
+
+struct Args {
+    style: FmtStyle,
+    write: bool,
+    debug_commands: bool,
+    inline_input: bool,
+    files: Vec<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let (mut indent, mut width) = match FmtStyle::default() {
+        FmtStyle::Pretty { indent, width } => (indent, width),
+        FmtStyle::Minified => unreachable!(),
+    };
+    let mut minify = false;
+    let mut write = false;
+    let mut debug_commands = false;
+    let mut inline_input = false;
+    let mut files = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| {
+            args.next()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| format!("`{name}` expects a number"))
+        };
+        match arg.as_str() {
+            "--minify" => minify = true,
+            "--write" => write = true,
+            "--debug-commands" => debug_commands = true,
+            "--inline-input" => inline_input = true,
+            "--indent" => indent = number("--indent")?,
+            "--width" => width = number("--width")?,
+            flag if flag.starts_with("--") => return Err(format!("unknown flag `{flag}`")),
+            _ => files.push(arg),
+        }
+    }
+
+    let style = match minify {
+        true => FmtStyle::Minified,
+        false => FmtStyle::Pretty { indent, width },
+    };
+    Ok(Args {
+        style,
+        write,
+        debug_commands,
+        inline_input,
+        files,
+    })
+}
+
+fn format(src: &[u8], args: &Args) -> Result<Vec<u8>, String> {
+    let mut parser = BfParser::new(src);
+    if args.debug_commands {
+        parser = parser.debug_commands();
+    }
+    let (bf, input, program_len) = match args.inline_input {
+        true => {
+            let (bf, input) = parser.parse_with_input().map_err(|e| e.to_string())?;
+            let program_len = src.iter().position(|&c| c == b'!').unwrap_or(src.len());
+            (bf, input, program_len)
+        }
+        false => (
+            parser.parse().map_err(|e| e.to_string())?,
+            vec![],
+            src.len(),
+        ),
+    };
+    let mut out = vec![];
+    BfSource::with_comments(&bf, &src[..program_len], args.style)
+        .write_to(&mut out)
+        .map_err(|e| e.to_string())?;
+    out.push(b'\n');
+    if program_len < src.len() {
+        out.push(b'!');
+        out.extend(input);
+    }
+    Ok(out)
+}
+
+fn run() -> Result<(), String> {
+    let args = parse_args()?;
+
+    if args.files.is_empty() {
+        if args.write {
+            return Err("`--write` needs at least one file".into());
+        }
+        let mut src = vec![];
+        stdin().read_to_end(&mut src).map_err(|e| e.to_string())?;
+        let out = format(&src, &args)?;
+        return stdout().write_all(&out).map_err(|e| e.to_string());
+    }
+
+    for path in &args.files {
+        let src = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+        let out = format(&src, &args).map_err(|e| format!("{path}: {e}"))?;
+        if args.write {
+            fs::write(path, out).map_err(|e| format!("{path}: {e}"))?;
+        } else {
+            stdout().write_all(&out).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bf_fmt: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,27 +1,157 @@
-use std::mem;
+use std::{
+    any::Any,
+    io::{self, Read, Write},
+    mem::{self, ManuallyDrop},
+    panic::{self, AssertUnwindSafe},
+};
 
 use cranelift::{
     codegen::{
         ir::{
-            types::{I32, I64, I8},
-            Function, StackSlot, UserFuncName,
+            types::{I32, I64, I8, I8X16},
+            FuncRef, Function, UserFuncName,
         },
-        isa::{CallConv, OwnedTargetIsa, TargetFrontendConfig},
+        isa::OwnedTargetIsa,
         verify_function,
     },
     prelude::*,
 };
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{default_libcall_names, Linkage, Module};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use target_lexicon::{Architecture, Triple};
+
+use crate::{
+    bf::{walk, Visit},
+    bf_ir::{BfIrScope, BfIrTok, MAX_CELL_COUNT},
+    io_utils::{self, ProgramIO},
+    opt::pass_manager::OptLevel,
+};
 
-use crate::bf_ir::{BfIrScope, BfIrTok, MAX_CELL_COUNT};
+/// The symbol of the host function which compiled code calls for `BfIrTok::Read`
+pub(crate) const READ_SYMBOL: &str = "bf_read";
+/// The symbol of the host function which compiled code calls for `BfIrTok::Write`
+pub(crate) const WRITE_SYMBOL: &str = "bf_write";
+
+/// Returned by the entrypoint when the program ran to completion
+pub(crate) const EXIT_FINISHED: i32 = 0;
+/// Returned by the entrypoint when the fuel ran out
+pub(crate) const EXIT_OUT_OF_FUEL: i32 = 1;
+/// Returned by the entrypoint when a host I/O function failed
+pub(crate) const EXIT_IO_ERROR: i32 = 2;
+/// Returned by the entrypoint when the program reached a `Scan` which can never finish, whether or not fuel is limited
+pub(crate) const EXIT_NEVER_HALTS: i32 = 3;
+
+/// The registers which are passed into the entrypoint by pointer, and written back when it returns
+#[repr(C)]
+struct JitRegs {
+    /// The number of loop iterations which may still be started. `u64::MAX` is effectively unlimited
+    fuel: u64,
+    data_ptr: usize,
+}
 
-pub fn dev_run() {
-    let shared_builder = settings::builder();
-    let shared_flags = settings::Flags::new(shared_builder);
+/// The offset of `JitRegs::fuel`
+pub(crate) const REGS_FUEL_OFFSET: i32 = 0;
+/// The offset of `JitRegs::data_ptr`
+pub(crate) const REGS_DATA_PTR_OFFSET: i32 = 8;
+/// The size of `JitRegs` on a target with 64-bit pointers, which is enough for smaller pointers
+pub(crate) const REGS_SIZE: u32 = 16;
+
+const _: () = assert!(mem::offset_of!(JitRegs, fuel) == REGS_FUEL_OFFSET as usize);
+const _: () = assert!(mem::offset_of!(JitRegs, data_ptr) == REGS_DATA_PTR_OFFSET as usize);
+
+/// The optimization level Cranelift compiles with, which maps to its `opt_level` setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodegenOptLevel {
+    /// Minimize compile time
+    None,
+    #[default]
+    Speed,
+    SpeedAndSize,
+}
+
+impl CodegenOptLevel {
+    fn setting(self) -> &'static str {
+        match self {
+            CodegenOptLevel::None => "none",
+            CodegenOptLevel::Speed => "speed",
+            CodegenOptLevel::SpeedAndSize => "speed_and_size",
+        }
+    }
+}
 
-    let isa = isa::lookup(target_lexicon::triple!("x86_64")).unwrap();
-    let isa = isa.finish(shared_flags).unwrap();
+/// Configures the target and Cranelift settings which programs are compiled with
+#[derive(Debug, Clone)]
+pub struct CodegenConfig {
+    /// The target to compile for. When `None`, the host ISA (including its CPU features) is detected
+    ///
+    /// Only programs compiled for the host can be run by the JIT
+    pub triple: Option<Triple>,
+    pub opt_level: CodegenOptLevel,
+    /// The passes which programs are optimized with before they are compiled
+    pub ir_opt_level: OptLevel,
+    /// Runs the Cranelift IR verifier during compilation
+    pub enable_verifier: bool,
+    /// Emits inline stack probes for large stack frames
+    pub enable_probestack: bool,
+    /// Any other Cranelift settings, as `(name, value)` pairs. These are applied last
+    pub settings: Vec<(String, String)>,
+}
+
+impl Default for CodegenConfig {
+    fn default() -> Self {
+        Self {
+            triple: None,
+            opt_level: CodegenOptLevel::default(),
+            ir_opt_level: OptLevel::default(),
+            enable_verifier: true,
+            enable_probestack: false,
+            settings: vec![],
+        }
+    }
+}
+
+impl CodegenConfig {
+    pub fn with_triple(mut self, triple: Triple) -> Self {
+        self.triple = Some(triple);
+        self
+    }
+    pub fn with_opt_level(mut self, opt_level: CodegenOptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+    pub fn with_ir_opt_level(mut self, ir_opt_level: OptLevel) -> Self {
+        self.ir_opt_level = ir_opt_level;
+        self
+    }
+    pub fn with_setting(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.settings.push((name.into(), value.into()));
+        self
+    }
+
+    /// Builds the `TargetIsa` described by this config
+    pub fn isa(&self) -> anyhow::Result<OwnedTargetIsa> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", self.opt_level.setting())?;
+        flags.set("enable_verifier", &self.enable_verifier.to_string())?;
+        flags.set("enable_probestack", &self.enable_probestack.to_string())?;
+        if self.enable_probestack {
+            // There is no probestack function to call out to
+            flags.set("probestack_strategy", "inline")?;
+        }
+        for (name, value) in &self.settings {
+            flags.set(name, value)?;
+        }
+
+        let isa = match &self.triple {
+            Some(triple) => isa::lookup(triple.clone())?,
+            None => cranelift_native::builder().map_err(anyhow::Error::msg)?,
+        };
+        Ok(isa.finish(settings::Flags::new(flags))?)
+    }
+}
+
+pub fn dev_run() {
+    let isa = CodegenConfig::default().isa().unwrap();
 
     println!("{}", isa);
 
@@ -38,68 +168,344 @@ pub fn dev_run() {
 
     println!("=====");
 
-    let f = compile(program, isa.frontend_config());
+    let mut io = io_utils::stdio_triple();
+    let program = JitProgram::new(program, isa).unwrap();
+    let f = program.function();
     std::fs::write("./bf_programs/compiled.clif", f.display().to_string()).unwrap();
     //
     println!("{}", f.display());
-    run(f, isa.to_owned());
+    program.run(&mut io).unwrap();
+}
+
+/// Lets the I/O trampolines call into any `ProgramIO` through a trait object
+trait ByteIo: Read + Write {}
+
+impl<T: Read + Write> ByteIo for T {}
+
+/// The state which the host I/O trampolines are called with, through a pointer passed into the entrypoint
+struct JitIo<'a> {
+    io: &'a mut dyn ByteIo,
+    /// The first I/O error hit by the program, which stops execution
+    err: Option<io::Error>,
+    /// Panics can't unwind through compiled code, so they're caught and resumed once it returns
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl JitIo<'_> {
+    /// Runs `f` on the inner `ProgramIO`, recording any error or panic. Returns `-1` on failure
+    fn call(&mut self, f: impl FnOnce(&mut dyn ByteIo) -> io::Result<i32>) -> i32 {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *self.io))) {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                self.err = Some(e);
+                -1
+            }
+            Err(p) => {
+                self.panic = Some(p);
+                -1
+            }
+        }
+    }
+}
+
+/// Reads a single byte from the program's `ProgramIO`, returning `-1` on failure
+extern "C" fn io_read(ctx: *mut JitIo) -> i32 {
+    let ctx = unsafe { &mut *ctx };
+    ctx.call(|io| {
+        let mut b = 0;
+        io.read_exact(std::array::from_mut(&mut b))?;
+        Ok(i32::from(b))
+    })
+}
+
+/// Writes a single byte to the program's `ProgramIO`, returning `-1` on failure
+extern "C" fn io_write(ctx: *mut JitIo, b: u8) -> i32 {
+    let ctx = unsafe { &mut *ctx };
+    ctx.call(|io| {
+        io.write_all(&[b])?;
+        Ok(0)
+    })
 }
 
 struct BuildCtx<'a, 'b> {
     builder: &'a mut FunctionBuilder<'b>,
 
-    targ_cfg: TargetFrontendConfig,
     /// `ptr` offset from start of data array (unsigned)
-    data_ptr: &'a StackSlot,
-    /// The stack slot storing the data array
-    data: &'a StackSlot,
-    data_size: u32,
+    data_ptr: Variable,
+    /// The remaining number of loop iterations. A `Scan` spends one for every cell it steps over
+    fuel: Variable,
+    /// The host-supplied pointer to the start of the data array
+    data: Value,
+    /// `data_len - 1`. The length is a power of two, so that wrapping offsets is a single mask
+    data_mask: Value,
+    /// Whether the target supports the 128-bit vectors used by `scan`
+    simd: bool,
+
+    /// The `JitIo` pointer passed into the entrypoint
+    io_ctx: Value,
+    read: FuncRef,
+    write: FuncRef,
+    /// The block which returns from the entrypoint, taking the exit status as a parameter
+    exit_block: Block,
 }
 
 impl BuildCtx<'_, '_> {
-    /// Gets a pointer to `self.data + self.data_ptr + offset`
-    pub fn addr_of_data(&mut self, offset: i32) -> Value {
-        let base = self
-            .builder
+    /// Calls the host I/O function `f`, exiting the program if it fails. Otherwise, returns its result
+    pub fn call_io(&mut self, f: FuncRef, args: &[Value]) -> Value {
+        let mut call_args = vec![self.io_ctx];
+        call_args.extend_from_slice(args);
+
+        let call = self.builder.ins().call(f, &call_args);
+        let res = self.builder.inst_results(call)[0];
+
+        let ok_block = self.builder.create_block();
+        let failed = self.builder.ins().icmp_imm(IntCC::SignedLessThan, res, 0);
+        let status = self.builder.ins().iconst(I32, i64::from(EXIT_IO_ERROR));
+        self.builder
             .ins()
-            .stack_addr(self.targ_cfg.pointer_type(), *self.data, offset);
-        // let ptr_offset = self.builder.use_var(*self.data_ptr);
-        let ptr_offset =
-            self.builder
-                .ins()
-                .stack_load(self.targ_cfg.pointer_type(), *self.data_ptr, 0);
-        let ptr = self.builder.ins().iadd(base, ptr_offset);
+            .brif(failed, self.exit_block, &[status], ok_block, &[]);
+        self.builder.switch_to_block(ok_block);
+
+        res
+    }
+    /// Gets `(self.data_ptr + offset) % data_len`
+    pub fn data_ptr_offset(&mut self, offset: isize) -> Value {
+        let ptr = self.builder.use_var(self.data_ptr);
+        if offset == 0 {
+            return ptr;
+        }
+        let ptr = self.builder.ins().iadd_imm(ptr, offset as i64);
+        self.builder.ins().band(ptr, self.data_mask)
+    }
+    /// Gets a pointer to `self.data + (self.data_ptr + offset) % data_len`
+    pub fn addr_of_data(&mut self, offset: isize) -> Value {
+        let ptr_offset = self.data_ptr_offset(offset);
+        let ptr = self.builder.ins().iadd(self.data, ptr_offset);
         ptr
     }
     /// Loads the byte at `self.data + self.data_ptr + offset`
-    pub fn load_data(&mut self, offset: i32) -> Value {
+    pub fn load_data(&mut self, offset: isize) -> Value {
         let ptr = self.addr_of_data(offset);
-        self.builder.ins().load(I8, MemFlags::new(), ptr, 0)
+        self.builder.ins().load(I8, MemFlags::trusted(), ptr, 0)
     }
-    pub fn store_data(&mut self, val: Value, offset: i32) {
+    pub fn store_data(&mut self, val: Value, offset: isize) {
         let ptr = self.addr_of_data(offset);
-        self.builder.ins().store(MemFlags::new(), val, ptr, 0);
+        self.builder.ins().store(MemFlags::trusted(), val, ptr, 0);
+    }
+    /// Spends `amount` units of fuel, an `I64`, continuing in a new block.
+    /// If there is less than `amount` left, jumps to `or_else` with `args` instead
+    fn spend_fuel(&mut self, amount: Value, or_else: Block, args: &[Value]) {
+        let fuel = self.builder.use_var(self.fuel);
+        let enough = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, fuel, amount);
+        let spend_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(enough, spend_block, &[], or_else, args);
+
+        self.builder.switch_to_block(spend_block);
+        let fuel = self.builder.ins().isub(fuel, amount);
+        self.builder.def_var(self.fuel, fuel);
+    }
+
+    /// Moves `self.data_ptr` by `stride` until it's on a zero cell, spending one unit of fuel per step like the loop would
+    ///
+    /// With SIMD, strides of `1` and `-1` check 16 cells at a time while they're all inside the tape,
+    /// spending 16 units to skip past them, or the distance to the first zero among them.
+    /// With less fuel than that, they're checked one at a time
+    pub fn scan(&mut self, stride: isize) {
+        if stride == 0 {
+            self.scan_in_place();
+            return;
+        }
+
+        let head_block = self.builder.create_block();
+        let scalar_block = self.builder.create_block();
+        let step_block = self.builder.create_block();
+        let found_block = self.builder.create_block();
+
+        self.builder.ins().jump(head_block, &[]);
+        self.builder.switch_to_block(head_block);
+
+        if self.simd && stride.abs() == 1 {
+            let vector_block = self.builder.create_block();
+            let vector_step_block = self.builder.create_block();
+            let vector_found_block = self.builder.create_block();
+            let ptr_ty = self.builder.func.dfg.value_type(self.data_mask);
+            self.builder.append_block_param(vector_found_block, ptr_ty);
+
+            // The 16 cells are the pointer and the ones after it, or before it when scanning left
+            let ptr = self.builder.use_var(self.data_ptr);
+            let in_bounds = if stride == 1 {
+                // Compared as `ptr + 15 <= data_mask`, which can't wrap around on tapes shorter than 16 cells
+                let last = self.builder.ins().iadd_imm(ptr, 15);
+                self.builder
+                    .ins()
+                    .icmp(IntCC::UnsignedLessThanOrEqual, last, self.data_mask)
+            } else {
+                self.builder
+                    .ins()
+                    .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, ptr, 15)
+            };
+            self.builder
+                .ins()
+                .brif(in_bounds, vector_block, &[], scalar_block, &[]);
+
+            self.builder.switch_to_block(vector_block);
+            let start = match stride {
+                1 => ptr,
+                _ => self.builder.ins().iadd_imm(ptr, -15),
+            };
+            let addr = self.builder.ins().iadd(self.data, start);
+            // Not necessarily aligned
+            let cells = self
+                .builder
+                .ins()
+                .load(I8X16, MemFlags::new().with_notrap(), addr, 0);
+            let zero = self.builder.ins().iconst(I8, 0);
+            let zeros = self.builder.ins().splat(I8X16, zero);
+            let is_zero = self.builder.ins().icmp(IntCC::Equal, cells, zeros);
+            // Bit `i` is set if cell `start + i` is zero
+            let mask = self.builder.ins().vhigh_bits(ptr_ty, is_zero);
+            self.builder
+                .ins()
+                .brif(mask, vector_found_block, &[mask], vector_step_block, &[]);
+
+            self.builder.switch_to_block(vector_step_block);
+            let window = self.builder.ins().iconst(I64, 16);
+            self.spend_fuel(window, scalar_block, &[]);
+            let next = self.data_ptr_offset(16 * stride);
+            self.builder.def_var(self.data_ptr, next);
+            self.builder.ins().jump(head_block, &[]);
+
+            self.builder.switch_to_block(vector_found_block);
+            let mask = self.builder.block_params(vector_found_block)[0];
+            let index = match stride {
+                1 => self.builder.ins().ctz(mask),
+                _ => {
+                    let leading = self.builder.ins().clz(mask);
+                    let top_bit = self
+                        .builder
+                        .ins()
+                        .iconst(ptr_ty, i64::from(ptr_ty.bits()) - 1);
+                    self.builder.ins().isub(top_bit, leading)
+                }
+            };
+            // The zero is `index` steps away, or `15 - index` when scanning left
+            let steps = match stride {
+                1 => index,
+                _ => self.builder.ins().irsub_imm(index, 15),
+            };
+            let steps = if ptr_ty == I64 {
+                steps
+            } else {
+                self.builder.ins().uextend(I64, steps)
+            };
+            self.spend_fuel(steps, scalar_block, &[]);
+            let found = self.builder.ins().iadd(start, index);
+            self.builder.def_var(self.data_ptr, found);
+            self.builder.ins().jump(found_block, &[]);
+        } else {
+            self.builder.ins().jump(scalar_block, &[]);
+        }
+
+        self.builder.switch_to_block(scalar_block);
+        let cell = self.load_data(0);
+        self.builder
+            .ins()
+            .brif(cell, step_block, &[], found_block, &[]);
+
+        self.builder.switch_to_block(step_block);
+        let status = self.builder.ins().iconst(I32, i64::from(EXIT_OUT_OF_FUEL));
+        let one = self.builder.ins().iconst(I64, 1);
+        self.spend_fuel(one, self.exit_block, &[status]);
+        let next = self.data_ptr_offset(stride);
+        self.builder.def_var(self.data_ptr, next);
+        self.builder.ins().jump(head_block, &[]);
+
+        self.builder.switch_to_block(found_block);
+    }
+
+    /// A `Scan` with a stride of `0` never leaves a nonzero cell, like `[]`, so it exits with `EXIT_NEVER_HALTS`.
+    /// Fuel is spent even when it's unlimited, so the host decides whether that means running out of fuel
+    fn scan_in_place(&mut self) {
+        let stuck_block = self.builder.create_block();
+        let done_block = self.builder.create_block();
+        let cell = self.load_data(0);
+        self.builder
+            .ins()
+            .brif(cell, stuck_block, &[], done_block, &[]);
+
+        self.builder.switch_to_block(stuck_block);
+        let status = self.builder.ins().iconst(I32, i64::from(EXIT_NEVER_HALTS));
+        self.builder.ins().jump(self.exit_block, &[status]);
+
+        self.builder.switch_to_block(done_block);
     }
 }
 
 /// Turns a `BfIrScope` into a series of blocks, starting with `curr_block`
-fn build_scope<'a>(sc: BfIrScope, ctx: &mut BuildCtx, curr_block: Block, return_to: Block) {
+fn build_scope(sc: BfIrScope, ctx: &mut BuildCtx, curr_block: Block, return_to: Block) {
     ctx.builder.switch_to_block(curr_block);
 
-    for tok in sc.as_ref() {
+    // For each loop being built, `(block which checks the loop condition, block to continue the enclosing scope in)`
+    let mut loops = vec![];
+    for visit in walk(&sc) {
+        let tok = match visit {
+            Visit::Tok { tok, .. } => tok,
+            Visit::End { .. } => {
+                let (pre_block, post_block) = loops.pop().unwrap();
+                ctx.builder.ins().jump(pre_block, &[]);
+                ctx.builder.switch_to_block(post_block);
+                continue;
+            }
+        };
+
         match tok {
-            // BfIrTok::Set(_) => todo!(),
-            // BfIrTok::Add(delta) => {
-            //     let old = ctx.load_data(0);
-            //     let new = ctx.builder.ins().iadd_imm(old, i64::from(delta.0));
-            //     ctx.store_data(new, 0);
-            // }
-            // BfIrTok::PtrAdd(_) => todo!(),
-            BfIrTok::Modify { adds, ptr_delta } => todo!(),
-            BfIrTok::Read => todo!(),
-            BfIrTok::Write => todo!(),
-            BfIrTok::Loop(inner) => {
+            BfIrTok::Modify { adds, ptr_delta } => {
+                for (offset, delta) in adds {
+                    let old = ctx.load_data(*offset);
+                    let new = ctx.builder.ins().iadd_imm(old, i64::from(delta.0));
+                    ctx.store_data(new, *offset);
+                }
+
+                if *ptr_delta != 0 {
+                    let new_ptr = ctx.data_ptr_offset(*ptr_delta);
+                    ctx.builder.def_var(ctx.data_ptr, new_ptr);
+                }
+            }
+            BfIrTok::Set(val) => {
+                let val = ctx.builder.ins().iconst(I8, i64::from(*val));
+                ctx.store_data(val, 0);
+            }
+            BfIrTok::MulAdd(factors) => {
+                let val = ctx.load_data(0);
+                for (offset, factor) in factors {
+                    let old = ctx.load_data(*offset);
+                    let product = ctx.builder.ins().imul_imm(val, i64::from(factor.0));
+                    let new = ctx.builder.ins().iadd(old, product);
+                    ctx.store_data(new, *offset);
+                }
+                let zero = ctx.builder.ins().iconst(I8, 0);
+                ctx.store_data(zero, 0);
+            }
+            BfIrTok::Read => {
+                let res = ctx.call_io(ctx.read, &[]);
+                let val = ctx.builder.ins().ireduce(I8, res);
+                ctx.store_data(val, 0);
+            }
+            BfIrTok::Write => {
+                let val = ctx.load_data(0);
+                ctx.call_io(ctx.write, &[val]);
+            }
+            BfIrTok::Scan(stride) => ctx.scan(*stride),
+            // Compiled code has no way to report the tape
+            BfIrTok::Debug => (),
+            BfIrTok::Loop(_) => {
                 let pre_block = ctx.builder.create_block();
+                let fuel_block = ctx.builder.create_block();
                 let inner_block = ctx.builder.create_block();
                 let post_block = ctx.builder.create_block();
 
@@ -107,9 +513,6 @@ fn build_scope<'a>(sc: BfIrScope, ctx: &mut BuildCtx, curr_block: Block, return_
                     ctx.builder.ins().jump(pre_block, &[]);
                 }
 
-                // `return_to` = `pre_block` to perform looping
-                build_scope(inner.clone(), ctx, inner_block, pre_block);
-
                 ctx.builder.switch_to_block(pre_block);
                 {
                     // If data == 0, skip (else)
@@ -117,53 +520,118 @@ fn build_scope<'a>(sc: BfIrScope, ctx: &mut BuildCtx, curr_block: Block, return_
                     let cond = ctx.load_data(0);
                     ctx.builder
                         .ins()
-                        .brif(cond, inner_block, &[], post_block, &[]);
+                        .brif(cond, fuel_block, &[], post_block, &[]);
                 }
 
-                ctx.builder.switch_to_block(post_block);
+                ctx.builder.switch_to_block(fuel_block);
+                {
+                    // Every iteration burns one unit of fuel, exiting once there is none left
+                    let fuel = ctx.builder.use_var(ctx.fuel);
+                    let dec_block = ctx.builder.create_block();
+                    let status = ctx.builder.ins().iconst(I32, i64::from(EXIT_OUT_OF_FUEL));
+                    ctx.builder
+                        .ins()
+                        .brif(fuel, dec_block, &[], ctx.exit_block, &[status]);
+
+                    ctx.builder.switch_to_block(dec_block);
+                    let fuel = ctx.builder.ins().iadd_imm(fuel, -1);
+                    ctx.builder.def_var(ctx.fuel, fuel);
+                    ctx.builder.ins().jump(inner_block, &[]);
+                }
+
+                // The body jumps back to `pre_block` to perform looping, and the enclosing scope continues in `post_block`
+                ctx.builder.switch_to_block(inner_block);
+                loops.push((pre_block, post_block));
             }
         }
     }
+    ctx.builder.ins().jump(return_to, &[]);
+}
 
-    // Should be switched to `curr_block`
-    {
-        ctx.builder.ins().jump(return_to, &[]);
-    }
+/// Declares an imported host function named `name` inside of `func`
+fn import_host_fn(
+    module: &mut impl Module,
+    func: &mut Function,
+    name: &str,
+    params: &[AbiParam],
+    returns: &[AbiParam],
+) -> anyhow::Result<FuncRef> {
+    let mut sig = module.make_signature();
+    sig.params.extend_from_slice(params);
+    sig.returns.extend_from_slice(returns);
+
+    let fid = module.declare_function(name, Linkage::Import, &sig)?;
+    Ok(module.declare_func_in_func(fid, func))
 }
 
-pub fn compile(sc: BfIrScope, targ_cfg: TargetFrontendConfig) -> Function {
-    let sig = Signature::new(CallConv::SystemV); // Our entrypoint has no arguments or returns
+/// Compiles `sc` into an entrypoint function, declaring the host functions it calls inside of `module`
+///
+/// The entrypoint is `fn(io_ctx, tape, tape_len, regs) -> status`:
+/// * `io_ctx` is passed through to the host functions
+/// * `tape` points to `tape_len` cells, where `tape_len` must be a power of two
+/// * `regs` points to a `u64` of fuel at `REGS_FUEL_OFFSET`, and the starting cell index at `REGS_DATA_PTR_OFFSET`.
+///   Both are updated when the entrypoint returns
+/// * `status` is one of the `EXIT_*` constants
+///
+/// Fails if `module` rejects the host function declarations, or if the verifier (when enabled) rejects the function
+pub fn compile(sc: BfIrScope, module: &mut impl Module) -> anyhow::Result<Function> {
+    let targ_cfg = module.target_config();
+    let ptr_ty = targ_cfg.pointer_type();
+    let simd = matches!(
+        module.isa().triple().architecture,
+        Architecture::X86_64 | Architecture::Aarch64(_)
+    );
+
+    let mut sig = module.make_signature();
+    sig.params.extend([AbiParam::new(ptr_ty); 4]);
+    sig.returns.push(AbiParam::new(I32));
     let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
+
+    let read = import_host_fn(
+        module,
+        &mut func,
+        READ_SYMBOL,
+        &[AbiParam::new(ptr_ty)],
+        &[AbiParam::new(I32)],
+    )?;
+    let write = import_host_fn(
+        module,
+        &mut func,
+        WRITE_SYMBOL,
+        // The byte is zero-extended, as C ABIs expect of a `u8` argument
+        &[AbiParam::new(ptr_ty), AbiParam::new(I8).uext()],
+        &[AbiParam::new(I32)],
+    )?;
+
     let mut func_builder_ctx = FunctionBuilderContext::new();
     let mut builder = FunctionBuilder::new(&mut func, &mut func_builder_ctx);
 
     let main_block = builder.create_block();
     let inner_block = builder.create_block();
     let post_main_block = builder.create_block();
+    let exit_block = builder.create_block();
+    builder.append_block_param(exit_block, I32);
 
-    // let data_ptr = Variable::new(0);
-    let data_ptr = builder.create_sized_stack_slot(StackSlotData::new(
-        StackSlotKind::ExplicitSlot,
-        targ_cfg.pointer_bytes() as u32,
-    ));
-    let data = builder.create_sized_stack_slot(StackSlotData::new(
-        StackSlotKind::ExplicitSlot,
-        MAX_CELL_COUNT as u32,
-    ));
+    let data_ptr = Variable::new(0);
+    builder.declare_var(data_ptr, ptr_ty);
+    let fuel = Variable::new(1);
+    builder.declare_var(fuel, I64);
 
+    builder.append_block_params_for_function_params(main_block);
     builder.switch_to_block(main_block);
+    let &[io_ctx, data, data_len, regs] = builder.block_params(main_block) else {
+        unreachable!()
+    };
+    let data_mask;
     {
-        let zero8 = builder.ins().iconst(I8, 0);
-        let zero_ptr = builder.ins().iconst(targ_cfg.pointer_type(), 0);
-        builder.ins().stack_store(zero_ptr, data_ptr, 0);
-
-        let base = builder.ins().stack_addr(targ_cfg.pointer_type(), data, 0);
-        let size = builder.ins().iconst(
-            targ_cfg.pointer_type(),
-            i64::try_from(MAX_CELL_COUNT).unwrap(),
-        );
-        println!("{size:?}");
-        builder.call_memset(targ_cfg, base, zero8, size);
+        let flags = MemFlags::trusted();
+        let fuel_init = builder.ins().load(I64, flags, regs, REGS_FUEL_OFFSET);
+        builder.def_var(fuel, fuel_init);
+        let data_ptr_init = builder
+            .ins()
+            .load(ptr_ty, flags, regs, REGS_DATA_PTR_OFFSET);
+        builder.def_var(data_ptr, data_ptr_init);
+        data_mask = builder.ins().iadd_imm(data_len, -1);
 
         builder.ins().jump(inner_block, &[]);
     }
@@ -173,10 +641,16 @@ pub fn compile(sc: BfIrScope, targ_cfg: TargetFrontendConfig) -> Function {
         &mut BuildCtx {
             builder: &mut builder,
 
-            targ_cfg,
-            data_ptr: &data_ptr,
-            data: &data,
-            data_size: MAX_CELL_COUNT as u32,
+            data_ptr,
+            fuel,
+            data,
+            data_mask,
+            simd,
+
+            io_ctx,
+            read,
+            write,
+            exit_block,
         },
         inner_block,
         post_main_block,
@@ -184,59 +658,203 @@ pub fn compile(sc: BfIrScope, targ_cfg: TargetFrontendConfig) -> Function {
 
     builder.switch_to_block(post_main_block);
     {
-        builder.ins().return_(&[]);
+        let status = builder.ins().iconst(I32, i64::from(EXIT_FINISHED));
+        builder.ins().jump(exit_block, &[status]);
+    }
+
+    builder.switch_to_block(exit_block);
+    {
+        let status = builder.block_params(exit_block)[0];
+        let flags = MemFlags::trusted();
+        let final_fuel = builder.use_var(fuel);
+        builder
+            .ins()
+            .store(flags, final_fuel, regs, REGS_FUEL_OFFSET);
+        let final_ptr = builder.use_var(data_ptr);
+        builder
+            .ins()
+            .store(flags, final_ptr, regs, REGS_DATA_PTR_OFFSET);
+        builder.ins().return_(&[status]);
     }
 
     builder.seal_all_blocks();
     builder.finalize();
 
-    println!("{}", func.display());
-    let flags = settings::Flags::new(settings::builder());
-    verify_function(&func, &flags).unwrap();
+    let flags = module.isa().flags();
+    if flags.enable_verifier() {
+        verify_function(&func, flags)?;
+    }
 
-    func
+    Ok(func)
 }
 
-pub fn run(f: Function, isa: OwnedTargetIsa) {
-    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
-    let mut ctx = module.make_context();
-    let mut _sig = module.make_signature();
-    _sig.call_conv = CallConv::SystemV;
+/// The tape of a JIT-compiled program, which is handed back to the host once a run finishes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitState {
+    /// The cells of the tape. The length must be a power of two
+    pub tape: Box<[u8]>,
+    pub data_ptr: usize,
+    /// The number of loop iterations which may still be started, or `None` if unlimited.
+    /// A `Scan` spends one for every cell it steps over, like its loop would.
+    /// Running out stops the program with `JitExit::OutOfFuel`
+    pub fuel: Option<u64>,
+}
 
-    let fid = module
-        .declare_function("main", Linkage::Local, &f.signature)
-        .unwrap();
-    ctx.func = f;
+/// How a run of a `JitProgram` stopped, when it didn't hit an I/O error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitExit {
+    Finished,
+    /// The program ran out of fuel at the start of a loop iteration, or partway through a `Scan`
+    OutOfFuel,
+    /// Without a fuel limit, the program reached a `Scan` which can never finish, with a stride of `0` on a nonzero cell
+    NeverHalts,
+}
 
-    module.define_function(fid, &mut ctx).unwrap();
-    module.clear_context(&mut ctx);
+impl JitState {
+    /// A zeroed tape of `MAX_CELL_COUNT` cells
+    pub fn new() -> Self {
+        Self::with_len(MAX_CELL_COUNT)
+    }
+    /// A zeroed tape of `len` cells
+    ///
+    /// Panics if `len` is not a power of two
+    pub fn with_len(len: usize) -> Self {
+        assert!(
+            len.is_power_of_two(),
+            "JIT tape length must be a power of two, got {len}"
+        );
+        Self {
+            tape: vec![0; len].into_boxed_slice(),
+            data_ptr: 0,
+            fuel: None,
+        }
+    }
+}
 
-    module.finalize_definitions().unwrap();
+impl Default for JitState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    let f_ptr = module.get_finalized_function(fid);
-    let f_ptr = unsafe { mem::transmute::<_, extern "C" fn() -> ()>(f_ptr) };
-    let _res = f_ptr();
+/// A BFIR program compiled by the JIT, which does its I/O through a `ProgramIO`
+pub struct JitProgram {
+    module: ManuallyDrop<JITModule>,
+    /// The entrypoint before it was compiled, kept around for inspection
+    func: Function,
+    entry: FuncId,
 }
 
-pub fn foo(isa: OwnedTargetIsa) {
-    let func = {
-        let sig = Signature::new(CallConv::SystemV); // Our entrypoint has no arguments or returns
-        let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
-        let mut func_builder_ctx = FunctionBuilderContext::new();
-        let mut builder = FunctionBuilder::new(&mut func, &mut func_builder_ctx);
+impl JitProgram {
+    /// Optimizes `sc` at `config.ir_opt_level` and compiles it for the ISA described by `config`, which must be the host
+    pub fn with_config(sc: BfIrScope, config: &CodegenConfig) -> anyhow::Result<Self> {
+        if let Some(triple) = &config.triple {
+            anyhow::ensure!(
+                *triple == Triple::host(),
+                "Cannot JIT for `{triple}` on a `{}` host",
+                Triple::host()
+            );
+        }
+        Self::new(config.ir_opt_level.optimize(sc), config.isa()?)
+    }
 
-        let main_block = builder.create_block();
+    pub fn new(sc: BfIrScope, isa: OwnedTargetIsa) -> anyhow::Result<Self> {
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol(READ_SYMBOL, io_read as *const u8);
+        builder.symbol(WRITE_SYMBOL, io_write as *const u8);
+        let mut module = JITModule::new(builder);
 
-        builder.switch_to_block(main_block);
-        {
-            builder.ins().return_(&[]);
-        }
+        let func = compile(sc, &mut module)?;
 
-        builder.seal_all_blocks();
-        builder.finalize();
+        let mut ctx = module.make_context();
+        let entry = module.declare_function("main", Linkage::Local, &func.signature)?;
+        ctx.func = func.clone();
 
-        func
-    };
+        module.define_function(entry, &mut ctx)?;
+        module.clear_context(&mut ctx);
+
+        module.finalize_definitions()?;
+
+        Ok(Self {
+            module: ManuallyDrop::new(module),
+            func,
+            entry,
+        })
+    }
+
+    /// The Cranelift IR of the entrypoint
+    pub fn function(&self) -> &Function {
+        &self.func
+    }
+
+    /// Runs the program on a fresh tape, stopping at the first I/O error
+    pub fn run(&self, io: &mut impl ProgramIO) -> io::Result<JitState> {
+        let mut state = JitState::new();
+        self.run_with_state(io, &mut state)?;
+        Ok(state)
+    }
 
-    run(func, isa);
+    /// Runs the program on the tape, data pointer and fuel of `state`,
+    /// stopping at the first I/O error or once the fuel runs out
+    ///
+    /// `state` is updated even when an error is returned
+    pub fn run_with_state(
+        &self,
+        io: &mut impl ProgramIO,
+        state: &mut JitState,
+    ) -> io::Result<JitExit> {
+        assert!(state.tape.len().is_power_of_two());
+        assert!(state.data_ptr < state.tape.len());
+
+        let f_ptr = self.module.get_finalized_function(self.entry);
+        let f_ptr = unsafe {
+            mem::transmute::<
+                *const u8,
+                extern "C" fn(*mut JitIo, *mut u8, usize, *mut JitRegs) -> i32,
+            >(f_ptr)
+        };
+
+        let mut ctx = JitIo {
+            io,
+            err: None,
+            panic: None,
+        };
+        let mut regs = JitRegs {
+            fuel: state.fuel.unwrap_or(u64::MAX),
+            data_ptr: state.data_ptr,
+        };
+        let status = f_ptr(
+            &mut ctx,
+            state.tape.as_mut_ptr(),
+            state.tape.len(),
+            &mut regs,
+        );
+        state.data_ptr = regs.data_ptr;
+        // Like the interpreter, a limited run spends all of its fuel on a `Scan` which can never finish
+        let (status, fuel) = match (status, state.fuel) {
+            (EXIT_NEVER_HALTS, Some(_)) => (EXIT_OUT_OF_FUEL, 0),
+            _ => (status, regs.fuel),
+        };
+        state.fuel = state.fuel.map(|_| fuel);
+
+        if let Some(p) = ctx.panic {
+            panic::resume_unwind(p);
+        }
+        if let Some(e) = ctx.err {
+            return Err(e);
+        }
+        match status {
+            EXIT_FINISHED => Ok(JitExit::Finished),
+            EXIT_OUT_OF_FUEL => Ok(JitExit::OutOfFuel),
+            EXIT_NEVER_HALTS => Ok(JitExit::NeverHalts),
+            _ => unreachable!("Unexpected exit status {status}"),
+        }
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        // Safety: no function pointers into the module outlive `self`
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() }
+    }
 }
@@ -0,0 +1,212 @@
+//! Ahead-of-time compilation of BFIR programs into relocatable object files
+//!
+//! The object contains the compiled program along with a small runtime which uses libc:
+//! * A C `main` which `calloc`s the tape and calls the program, exiting with the program's `EXIT_*` status
+//!   (or `EXIT_ALLOC_FAILED` if the tape couldn't be allocated)
+//! * The program's `Read`/`Write` host functions, using `getchar`/`putchar`
+//!
+//! Linking the object with a C toolchain (see `link_executable`) produces a standalone executable
+
+use std::{path::Path, process::Command};
+
+use cranelift::{
+    codegen::ir::{
+        types::{I32, I64},
+        Function, UserFuncName,
+    },
+    prelude::*,
+};
+use cranelift_module::{default_libcall_names, FuncId, FuncOrDataId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use crate::{
+    bf_ir::{BfIrScope, MAX_CELL_COUNT},
+    compile_cranelift::{
+        self, CodegenConfig, READ_SYMBOL, REGS_DATA_PTR_OFFSET, REGS_FUEL_OFFSET, REGS_SIZE,
+        WRITE_SYMBOL,
+    },
+};
+
+/// The symbol of the compiled program's entrypoint inside of the object
+const ENTRY_SYMBOL: &str = "bf_entry";
+
+/// The exit code of `main` when `calloc` fails, which is distinct from every `EXIT_*` status of the program
+pub(crate) const EXIT_ALLOC_FAILED: i32 = 4;
+
+/// Optimizes `sc` at `config.ir_opt_level` and compiles it into the bytes of a relocatable object file for the target of `config`
+///
+/// On EOI, reads emit `0`, like `io_utils::EOIEmit<0>`
+pub fn compile_object(sc: BfIrScope, config: &CodegenConfig) -> anyhow::Result<Vec<u8>> {
+    // Executables are position-independent by default on most toolchains
+    let isa = config.clone().with_setting("is_pic", "true").isa()?;
+    let mut module = ObjectModule::new(ObjectBuilder::new(
+        isa,
+        "bf_program",
+        default_libcall_names(),
+    )?);
+
+    let entry_func = compile_cranelift::compile(config.ir_opt_level.optimize(sc), &mut module)?;
+    let entry = module.declare_function(ENTRY_SYMBOL, Linkage::Local, &entry_func.signature)?;
+    define(&mut module, entry, entry_func)?;
+
+    let read = define_host_fn(&mut module, READ_SYMBOL)?;
+    define(&mut module, read.0, read.1)?;
+    let write = define_host_fn(&mut module, WRITE_SYMBOL)?;
+    define(&mut module, write.0, write.1)?;
+
+    let main = build_main(&mut module, entry)?;
+    define(&mut module, main.0, main.1)?;
+
+    Ok(module.finish().emit()?)
+}
+
+/// Links an object emitted by `compile_object` into an executable at `out`
+///
+/// Uses the C compiler in the `CC` environment variable, or `cc` by default
+pub fn link_executable(object: &Path, out: &Path) -> anyhow::Result<()> {
+    let cc = std::env::var_os("CC").unwrap_or("cc".into());
+    let status = Command::new(&cc).arg(object).arg("-o").arg(out).status()?;
+    anyhow::ensure!(
+        status.success(),
+        "`{}` failed to link `{}`: {status}",
+        cc.to_string_lossy(),
+        object.display()
+    );
+    Ok(())
+}
+
+fn define(module: &mut ObjectModule, id: FuncId, func: Function) -> anyhow::Result<()> {
+    let mut ctx = module.make_context();
+    ctx.func = func;
+    module.define_function(id, &mut ctx)?;
+    Ok(())
+}
+
+/// Declares a libc function which is imported into `func`
+fn import_libc(
+    module: &mut ObjectModule,
+    func: &mut Function,
+    name: &str,
+    params: &[Type],
+    ret: Type,
+) -> anyhow::Result<codegen::ir::FuncRef> {
+    let mut sig = module.make_signature();
+    sig.params
+        .extend(params.iter().map(|&ty| AbiParam::new(ty)));
+    sig.returns.push(AbiParam::new(ret));
+    let id = module.declare_function(name, Linkage::Import, &sig)?;
+    Ok(module.declare_func_in_func(id, func))
+}
+
+/// Builds the body of the host function `name`, which `compile` imported into the module
+///
+/// The I/O context pointer is ignored, since the runtime always uses the C stdio
+fn define_host_fn(module: &mut ObjectModule, name: &str) -> anyhow::Result<(FuncId, Function)> {
+    let Some(FuncOrDataId::Func(id)) = module.get_name(name) else {
+        anyhow::bail!("`{name}` was not declared by the compiled program");
+    };
+    let sig = module
+        .declarations()
+        .get_function_decl(id)
+        .signature
+        .clone();
+    // Turns the import into a definition
+    let id = module.declare_function(name, Linkage::Local, &sig)?;
+
+    let mut func = Function::with_name_signature(UserFuncName::user(0, id.as_u32()), sig);
+    let is_read = name == READ_SYMBOL;
+    let libc_fn = if is_read {
+        import_libc(module, &mut func, "getchar", &[], I32)?
+    } else {
+        import_libc(module, &mut func, "putchar", &[I32], I32)?
+    };
+
+    let mut func_builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut func, &mut func_builder_ctx);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+
+    let res = if is_read {
+        let call = builder.ins().call(libc_fn, &[]);
+        let c = builder.inst_results(call)[0];
+        // `EOF` is negative, and reads as `0`
+        let zero = builder.ins().iconst(I32, 0);
+        builder.ins().smax(c, zero)
+    } else {
+        let b = builder.block_params(block)[1];
+        let c = builder.ins().uextend(I32, b);
+        let call = builder.ins().call(libc_fn, &[c]);
+        let written = builder.inst_results(call)[0];
+        // `EOF` is negative, and stops the program
+        let zero = builder.ins().iconst(I32, 0);
+        builder.ins().smin(written, zero)
+    };
+    builder.ins().return_(&[res]);
+
+    builder.seal_all_blocks();
+    builder.finalize();
+
+    Ok((id, func))
+}
+
+/// Builds `int main(int argc, char **argv)`, which allocates a zeroed tape and runs `entry` on it
+fn build_main(module: &mut ObjectModule, entry: FuncId) -> anyhow::Result<(FuncId, Function)> {
+    let ptr_ty = module.target_config().pointer_type();
+
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(I32));
+    sig.params.push(AbiParam::new(ptr_ty));
+    sig.returns.push(AbiParam::new(I32));
+    let id = module.declare_function("main", Linkage::Export, &sig)?;
+
+    let mut func = Function::with_name_signature(UserFuncName::user(0, id.as_u32()), sig);
+    let calloc = import_libc(module, &mut func, "calloc", &[ptr_ty, ptr_ty], ptr_ty)?;
+    let entry = module.declare_func_in_func(entry, &mut func);
+
+    let mut func_builder_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut func, &mut func_builder_ctx);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+
+    let tape_len = builder.ins().iconst(ptr_ty, MAX_CELL_COUNT as i64);
+    let one = builder.ins().iconst(ptr_ty, 1);
+    let call = builder.ins().call(calloc, &[tape_len, one]);
+    let tape = builder.inst_results(call)[0];
+
+    let run_block = builder.create_block();
+    let oom_block = builder.create_block();
+    builder.ins().brif(tape, run_block, &[], oom_block, &[]);
+
+    builder.switch_to_block(run_block);
+    {
+        // Unlimited fuel, starting on the first cell
+        let regs_slot = builder
+            .create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, REGS_SIZE));
+        let fuel = builder.ins().iconst(I64, -1);
+        builder.ins().stack_store(fuel, regs_slot, REGS_FUEL_OFFSET);
+        let null = builder.ins().iconst(ptr_ty, 0);
+        builder
+            .ins()
+            .stack_store(null, regs_slot, REGS_DATA_PTR_OFFSET);
+        let regs = builder.ins().stack_addr(ptr_ty, regs_slot, 0);
+
+        let call = builder.ins().call(entry, &[null, tape, tape_len, regs]);
+
+        // `EXIT_FINISHED` is `0`, so only failed runs exit unsuccessfully
+        let status = builder.inst_results(call)[0];
+        builder.ins().return_(&[status]);
+    }
+
+    builder.switch_to_block(oom_block);
+    {
+        let exit_code = builder.ins().iconst(I32, i64::from(EXIT_ALLOC_FAILED));
+        builder.ins().return_(&[exit_code]);
+    }
+
+    builder.seal_all_blocks();
+    builder.finalize();
+
+    Ok((id, func))
+}
@@ -1,18 +1,308 @@
 use std::{
-    io::{stdin, stdout, Read, Stdin, Stdout, Write},
+    collections::HashSet,
+    fmt::Display,
+    io::{self, stdin, stdout, Read, Stdin, Stdout, Write},
     num::Wrapping,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    bf::{walk, Span, Visit},
     bf_ir::{BfIrScope, BfIrTok, MAX_CELL_COUNT},
     io_utils::{self, ProgramIO},
+    math,
+    opt::pass_manager::OptLevel,
+    profile::{LoopProfile, LoopStats},
 };
 
+/// A flattened BFIR instruction, where loops are replaced by jumps
+#[derive(Debug, Clone)]
+enum Op {
+    Modify {
+        adds: Box<[(isize, Wrapping<i8>)]>,
+        ptr_delta: isize,
+    },
+    Set(u8),
+    MulAdd(Box<[(isize, Wrapping<i8>)]>),
+    Scan(isize),
+    Write,
+    Read,
+    Debug {
+        span: Span,
+    },
+    /// If the current cell is `0`, jumps past the `LoopEnd` at `end`
+    LoopStart {
+        end: usize,
+    },
+    /// If the current cell is not `0`, jumps past the `LoopStart` at `start`
+    LoopEnd {
+        start: usize,
+    },
+}
+
+/// Where a flattened instruction came from in the `BfIrScope`
+#[derive(Debug, Clone, Copy)]
+struct OpOrigin {
+    /// The `LoopStart` of the loop containing this instruction
+    parent: Option<usize>,
+    /// The index of the token in its scope. A `LoopEnd` is one past the last token of its loop
+    index: usize,
+    /// The span of the token. A `LoopEnd` has the span of its loop
+    span: Span,
+}
+
+/// Flattens `sc` into a list of `Op`s, with precomputed jump targets for every loop
+///
+/// Also returns the origin of every op
+fn compile_ops(sc: &BfIrScope) -> (Vec<Op>, Vec<OpOrigin>) {
+    let mut ops = vec![];
+    let mut origins: Vec<OpOrigin> = vec![];
+    // The index of the `LoopStart` of each loop being visited
+    let mut loop_starts = vec![];
+
+    for visit in walk(sc) {
+        let parent = loop_starts.last().copied();
+        let (scope, index, tok) = match visit {
+            Visit::Tok {
+                scope, index, tok, ..
+            } => (scope, index, tok),
+            Visit::End { body, .. } => {
+                let start = loop_starts.pop().unwrap();
+                let end = ops.len();
+                ops.push(Op::LoopEnd { start });
+                let span = origins[start].span;
+                origins.push(OpOrigin {
+                    parent: Some(start),
+                    index: body.len_flat(),
+                    span,
+                });
+                ops[start] = Op::LoopStart { end };
+                continue;
+            }
+        };
+        let span = scope.spans()[index];
+        origins.push(OpOrigin {
+            parent,
+            index,
+            span,
+        });
+
+        match tok {
+            BfIrTok::Modify { adds, ptr_delta } => ops.push(Op::Modify {
+                adds: adds.iter().copied().collect(),
+                ptr_delta: *ptr_delta,
+            }),
+            BfIrTok::Set(val) => ops.push(Op::Set(*val)),
+            BfIrTok::MulAdd(factors) => ops.push(Op::MulAdd(factors.iter().copied().collect())),
+            BfIrTok::Scan(stride) => ops.push(Op::Scan(*stride)),
+            BfIrTok::Write => ops.push(Op::Write),
+            BfIrTok::Read => ops.push(Op::Read),
+            BfIrTok::Debug => ops.push(Op::Debug { span }),
+            BfIrTok::Loop(_) => {
+                loop_starts.push(ops.len());
+                // The jump target is filled in once the end of the loop is reached
+                ops.push(Op::LoopStart { end: usize::MAX });
+            }
+        }
+    }
+
+    (ops, origins)
+}
+
+/// The location of an instruction in an `Interpreter`'s flattened program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InsLocation(pub usize);
+
+/// Why stepping or resuming an `Interpreter` stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested instructions were executed, and the program can be resumed
+    Paused,
+    /// The program ran to completion
+    Finished,
+    /// The program is about to execute the instruction at a breakpoint
+    Breakpoint(InsLocation),
+    /// An instruction changed the value of a watched cell
+    Watchpoint { cell: usize, old: u8, new: u8 },
+    /// A `BfIrTok::Debug` was executed
+    Debug(TapeDump),
+}
+
+/// The number of cells on either side of the pointer which are included in a `TapeDump`
+pub const DUMP_RADIUS: usize = 8;
+
+/// A window of the tape around the pointer, taken when executing a `BfIrTok::Debug`
+///
+/// `Interpreter::run` writes these to the program's stderr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeDump {
+    /// The location of the `BfIrTok::Debug`
+    pub location: InsLocation,
+    pub span: Span,
+    pub data_ptr: usize,
+    /// The index of the first cell in `cells`
+    pub start: usize,
+    /// Up to `DUMP_RADIUS` cells on either side of the pointer, stopping at the ends of the tape
+    pub cells: Vec<u8>,
+}
+
+impl Display for TapeDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "# at {} (instruction {}), data_ptr = {}, cells {}..{}:",
+            self.span,
+            self.location.0,
+            self.data_ptr,
+            self.start,
+            self.start + self.cells.len()
+        )?;
+        for (i, cell) in self.cells.iter().enumerate() {
+            match self.start + i == self.data_ptr {
+                true => write!(f, " [{cell}]")?,
+                false => write!(f, " {cell}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Execution counters for every op, collected while profiling
+#[derive(Debug, Clone)]
+struct OpCounts {
+    /// The number of times each op was executed
+    executed: Vec<u64>,
+    /// The fuel spent by each op, which also counts the steps of a `Scan`
+    cost: Vec<u64>,
+    /// For each `LoopStart`, the number of times its loop body was entered
+    iterations: Vec<u64>,
+}
+
+impl OpCounts {
+    fn new(len: usize) -> Self {
+        Self {
+            executed: vec![0; len],
+            cost: vec![0; len],
+            iterations: vec![0; len],
+        }
+    }
+
+    #[inline(always)]
+    fn record(&mut self, ops: &[Op], ins_ptr: usize, new_ins_ptr: usize, cost: u64) {
+        self.executed[ins_ptr] += 1;
+        self.cost[ins_ptr] += cost;
+        match ops[ins_ptr] {
+            Op::LoopStart { .. } if new_ins_ptr == ins_ptr + 1 => self.iterations[ins_ptr] += 1,
+            Op::LoopEnd { start } if new_ins_ptr == start + 1 => self.iterations[start] += 1,
+            _ => (),
+        }
+    }
+}
+
+/// Stops the program when `cell` changes, optionally only when it changes to `value`
+#[derive(Debug, Clone)]
+struct Watchpoint {
+    cell: usize,
+    value: Option<u8>,
+    /// The value of the cell as of the last instruction
+    last: u8,
+}
+
+/// Statistics about a run which finished without errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub elapsed: Duration,
+    /// The number of flattened instructions which were executed.
+    /// Every check of a loop condition counts as one instruction
+    pub instructions: u64,
+}
+
+/// An error which stopped a run early
+#[derive(Debug)]
+pub enum RunError {
+    /// Reading a byte failed, including reaching EOI with `io_utils::EOIPanic`
+    Read(io::Error),
+    /// Writing a byte, or writing a `TapeDump` to stderr, failed
+    Write(io::Error),
+    /// The fuel given by `Interpreter::set_fuel` ran out before the program finished.
+    /// The program can continue with more fuel
+    ///
+    /// A `Scan` which can never reach a zero cell spends all the fuel left
+    OutOfFuel,
+    /// Without a fuel limit, a `Scan` could never reach a zero cell, so its loop would never finish
+    NeverHalts,
+}
+
+impl RunError {
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            RunError::Read(e) | RunError::Write(e) => Some(e),
+            RunError::OutOfFuel | RunError::NeverHalts => None,
+        }
+    }
+    pub fn into_io_error(self) -> Option<io::Error> {
+        match self {
+            RunError::Read(e) | RunError::Write(e) => Some(e),
+            RunError::OutOfFuel | RunError::NeverHalts => None,
+        }
+    }
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Read(e) => write!(f, "failed to read program input: {e}"),
+            RunError::Write(e) => write!(f, "failed to write program output: {e}"),
+            RunError::OutOfFuel => write!(f, "ran out of fuel"),
+            RunError::NeverHalts => write!(f, "never halts, a scan can't reach a zero cell"),
+        }
+    }
+}
+
+impl std::error::Error for RunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.io_error().map(|e| e as _)
+    }
+}
+
 struct RtData<IO> {
     stdio: IO,
     data: Box<[Wrapping<u8>]>,
     data_ptr: usize,
+    /// The index of the next op to execute
+    ins_ptr: usize,
+    /// The total number of ops executed
+    executed: u64,
+    /// The fuel left, or `None` if unlimited. Every op spends one unit, and a `Scan` also spends one per step
+    fuel: Option<u64>,
+}
+
+impl<IO> RtData<IO> {
+    /// Creates a zeroed tape of `MAX_CELL_COUNT` cells
+    fn new(stdio: IO) -> Self {
+        Self {
+            stdio,
+            data: vec![Wrapping(0); MAX_CELL_COUNT].into_boxed_slice(),
+            data_ptr: 0,
+            ins_ptr: 0,
+            executed: 0,
+            fuel: None,
+        }
+    }
+}
+
+impl<IO> RtData<IO> {
+    fn tape_dump(&self, ins_ptr: usize, span: Span) -> TapeDump {
+        let start = self.data_ptr.saturating_sub(DUMP_RADIUS);
+        let end = (self.data_ptr + DUMP_RADIUS + 1).min(self.data.len());
+        TapeDump {
+            location: InsLocation(ins_ptr),
+            span,
+            data_ptr: self.data_ptr,
+            start,
+            cells: self.data[start..end].iter().map(|c| c.0).collect(),
+        }
+    }
 }
 
 impl<IO: ProgramIO> RtData<IO> {
@@ -21,81 +311,209 @@ impl<IO: ProgramIO> RtData<IO> {
         self.data[self.data_ptr] = f(self.data[self.data_ptr])
     }
 
+    /// Gets `data_ptr + offset`, wrapping around both ends of `data`
     fn data_ptr_offset(&self, offset: isize) -> usize {
-        self.data_ptr
-            .checked_add_signed(offset)
-            .unwrap_or(self.data.len() - 1)
-            % self.data.len()
+        (self.data_ptr as isize + offset).rem_euclid(self.data.len() as isize) as usize
     }
 
-    fn run_scope(&mut self, sc: BfIrScope) {
-        let mut ins_ptr = 0;
+    /// The number of steps of `stride` from `data_ptr` to the first zero cell, as a loop such as `[>]` would take
+    ///
+    /// Searches with `memchr` for strides of `1` and `-1`.
+    /// Returns `None` if there is no zero cell among the cells which the stride can reach,
+    /// such as with a stride of `0` on a nonzero cell
+    fn scan(&self, stride: isize) -> Option<usize> {
+        // SAFETY: `Wrapping<u8>` is `repr(transparent)`
+        let tape =
+            unsafe { std::slice::from_raw_parts(self.data.as_ptr().cast::<u8>(), self.data.len()) };
+        let ptr = self.data_ptr;
+        // Never moves, like `[]`
+        if stride == 0 {
+            return (tape[ptr] == 0).then_some(0);
+        }
+        let step = stride.unsigned_abs();
 
-        loop {
-            let Some(ins) = sc.get(ins_ptr) else { return };
-
-            // Do not initialize, to force an assignment of the instruction pointer in every branch
-            let new_ins_ptr: usize;
-
-            match ins {
-                // BfIrTok::Set(n) => {
-                //     self.modify_data(|_| Wrapping(*n));
-                //     new_ins_ptr = ins_ptr + 1;
-                // }
-                // BfIrTok::Add(n) => {
-                //     self.modify_data(|prev| Wrapping(prev.0.wrapping_add_signed(n.0)));
-                //     new_ins_ptr = ins_ptr + 1;
-                // }
-                // BfIrTok::PtrAdd(delta) => {
-                //     // We wrap `data_ptr` around
-                //     self.data_ptr = self
-                //         .data_ptr
-                //         .checked_add_signed(*delta)
-                //         .unwrap_or(self.data.len() - 1)
-                //         % self.data.len();
-                //     new_ins_ptr = ins_ptr + 1;
-                // }
-                BfIrTok::Modify { adds, ptr_delta } => {
-                    for (offset, delta) in adds {
-                        let p = self.data_ptr_offset(*offset);
-                        self.data[p].0 = self.data[p].0.wrapping_add_signed(delta.0);
+        let found = match stride {
+            1 => memchr::memchr(0, &tape[ptr..]).map(|i| ptr + i),
+            -1 => memchr::memrchr(0, &tape[..=ptr]),
+            2.. => tape[ptr..]
+                .iter()
+                .step_by(step)
+                .position(|&c| c == 0)
+                .map(|i| ptr + i * step),
+            _ => tape[..=ptr]
+                .iter()
+                .rev()
+                .step_by(step)
+                .position(|&c| c == 0)
+                .map(|i| ptr - i * step),
+        };
+
+        if let Some(found) = found {
+            return Some(found.abs_diff(ptr) / step);
+        }
+        // Wraps around the ends of the tape one step at a time, like the loop would, until it's back at the start
+        let reachable = tape.len() / math::gcd(step, tape.len());
+        let mut ptr = ptr;
+        for steps in 0..reachable {
+            if tape[ptr] == 0 {
+                return Some(steps);
+            }
+            ptr = (ptr as isize + stride).rem_euclid(tape.len() as isize) as usize;
+        }
+        None
+    }
+
+    /// Executes the op at `ins_ptr`, returning the index of the next op.
+    /// `fuel` is the fuel left once the op itself is paid for, which a `Scan` spends more of
+    ///
+    /// On an I/O error, nothing is changed. A `Scan` which runs out of fuel keeps the steps it paid for
+    #[inline(always)]
+    fn exec(&mut self, ins: &Op, ins_ptr: usize, fuel: &mut u64) -> Result<usize, RunError> {
+        // Do not initialize, to force an assignment of the instruction pointer in every branch
+        let new_ins_ptr: usize;
+
+        match ins {
+            Op::Modify { adds, ptr_delta } => {
+                for &(offset, delta) in adds.iter() {
+                    let p = self.data_ptr_offset(offset);
+                    self.data[p].0 = self.data[p].0.wrapping_add_signed(delta.0);
+                }
+
+                self.data_ptr = self.data_ptr_offset(*ptr_delta);
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::Set(val) => {
+                self.modify_data(|_| Wrapping(*val));
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::MulAdd(factors) => {
+                let val = self.data[self.data_ptr].0;
+                if val != 0 {
+                    for &(offset, factor) in factors.iter() {
+                        let p = self.data_ptr_offset(offset);
+                        self.data[p].0 = self.data[p]
+                            .0
+                            .wrapping_add(val.wrapping_mul(factor.0 as u8));
+                    }
+                    self.modify_data(|_| Wrapping(0));
+                }
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::Scan(stride) => {
+                let Some(steps) = self.scan(*stride) else {
+                    if self.fuel.is_none() {
+                        return Err(RunError::NeverHalts);
                     }
+                    *fuel = 0;
+                    return Err(RunError::OutOfFuel);
+                };
+                if steps as u64 > *fuel {
+                    // The `Scan` is executed again once refueled, continuing from here
+                    self.data_ptr = self.data_ptr_offset(*stride * *fuel as isize);
+                    *fuel = 0;
+                    return Err(RunError::OutOfFuel);
+                }
+                *fuel -= steps as u64;
+                self.data_ptr = self.data_ptr_offset(*stride * steps as isize);
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::Read => {
+                let mut new_val = 0;
+                self.stdio
+                    .read_exact(std::array::from_mut(&mut new_val))
+                    .map_err(RunError::Read)?;
+                self.modify_data(|_| Wrapping(new_val));
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::Write => {
+                let to_write = self.data[self.data_ptr].0;
+                self.stdio.write_all(&[to_write]).map_err(RunError::Write)?;
+
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::Debug { span } => {
+                let dump = self.tape_dump(ins_ptr, *span);
+                writeln!(self.stdio.stderr(), "{dump}").map_err(RunError::Write)?;
 
-                    self.data_ptr = self.data_ptr_offset(*ptr_delta);
+                new_ins_ptr = ins_ptr + 1;
+            }
+            Op::LoopStart { end } => {
+                if self.data[self.data_ptr].0 == 0 {
+                    new_ins_ptr = end + 1;
+                } else {
                     new_ins_ptr = ins_ptr + 1;
                 }
-                BfIrTok::Read => {
-                    let mut new_val = 0;
-                    self.stdio
-                        .read_exact(std::array::from_mut(&mut new_val))
-                        .unwrap();
-                    self.modify_data(|_| Wrapping(new_val));
+            }
+            Op::LoopEnd { start } => {
+                if self.data[self.data_ptr].0 != 0 {
+                    new_ins_ptr = start + 1;
+                } else {
                     new_ins_ptr = ins_ptr + 1;
                 }
-                BfIrTok::Write => {
-                    let to_write = self.data[self.data_ptr].0;
-                    self.stdio.write_all(&[to_write]).unwrap();
+            }
+        }
 
-                    new_ins_ptr = ins_ptr + 1;
-                }
-                BfIrTok::Loop(inner) => {
-                    if self.data[self.data_ptr].0 != 0 {
-                        self.run_scope(inner.clone());
-                        new_ins_ptr = ins_ptr;
-                    } else {
-                        // Don't run
-                        new_ins_ptr = ins_ptr + 1;
-                    }
+        Ok(new_ins_ptr)
+    }
+
+    /// Runs `ops` from `self.ins_ptr` to completion, returning the number of instructions executed
+    ///
+    /// Calls `on_exec(ins_ptr, new_ins_ptr, cost)` after executing each op, where `cost` is the fuel it spent
+    #[inline(always)]
+    fn run_ops(
+        &mut self,
+        ops: &[Op],
+        mut on_exec: impl FnMut(usize, usize, u64),
+    ) -> Result<u64, RunError> {
+        let mut ins_ptr = self.ins_ptr;
+        let mut executed = 0;
+        let mut fuel = self.fuel.unwrap_or(u64::MAX);
+
+        let res = loop {
+            let Some(ins) = ops.get(ins_ptr) else {
+                break Ok(executed);
+            };
+            if fuel == 0 {
+                break Err(RunError::OutOfFuel);
+            }
+
+            let mut fuel_left = fuel - 1;
+            let res = self.exec(ins, ins_ptr, &mut fuel_left);
+            let cost = fuel - fuel_left;
+            // I/O errors don't spend any fuel
+            if !matches!(res, Err(ref e) if e.io_error().is_some()) {
+                fuel = fuel_left;
+            }
+            match res {
+                Ok(new_ins_ptr) => {
+                    on_exec(ins_ptr, new_ins_ptr, cost);
+                    ins_ptr = new_ins_ptr;
                 }
+                Err(e) => break Err(e),
             }
-            ins_ptr = new_ins_ptr;
+            executed += 1;
+        };
+
+        self.ins_ptr = ins_ptr;
+        self.executed += executed;
+        if let Some(f) = &mut self.fuel {
+            *f = fuel;
         }
+        res
     }
 }
 
 pub struct Interpreter<IO> {
     program: BfIrScope,
+    ops: Vec<Op>,
+    origins: Vec<OpOrigin>,
     data: RtData<IO>,
+    breakpoints: HashSet<usize>,
+    /// The breakpoint which execution last stopped at, until its instruction is executed
+    last_stop: Option<usize>,
+    watchpoints: Vec<Watchpoint>,
+    /// Set while profiling
+    counts: Option<OpCounts>,
 }
 
 impl Interpreter<()> {
@@ -106,15 +524,199 @@ impl Interpreter<()> {
 
 impl<IO> Interpreter<IO> {
     pub fn new(program: BfIrScope, io: IO) -> Interpreter<IO> {
-        let data = vec![Wrapping(0); MAX_CELL_COUNT].into_boxed_slice();
+        let (ops, origins) = compile_ops(&program);
         Self {
+            ops,
+            origins,
             program,
-            data: RtData {
-                stdio: io,
-                data,
-                data_ptr: 0,
-            },
+            data: RtData::new(io),
+            breakpoints: HashSet::new(),
+            last_stop: None,
+            watchpoints: vec![],
+            counts: None,
+        }
+    }
+
+    /// Optimizes `program` at `opt_level` before running it
+    pub fn with_opt_level(program: BfIrScope, io: IO, opt_level: OptLevel) -> Interpreter<IO> {
+        Self::new(opt_level.optimize(program), io)
+    }
+
+    /// The cells of the tape
+    pub fn tape(&self) -> &[Wrapping<u8>] {
+        &self.data.data
+    }
+    pub fn tape_mut(&mut self) -> &mut [Wrapping<u8>] {
+        &mut self.data.data
+    }
+    /// The index of the current cell
+    pub fn data_ptr(&self) -> usize {
+        self.data.data_ptr
+    }
+    /// Panics if `data_ptr` is past the end of the tape
+    pub fn set_data_ptr(&mut self, data_ptr: usize) {
+        assert!(data_ptr < self.data.data.len());
+        self.data.data_ptr = data_ptr;
+    }
+    /// Limits the number of instructions which may be executed from now on, or removes the limit if `None`.
+    /// A `Scan` also spends one unit of fuel for every step it takes.
+    /// Running out stops the program with `RunError::OutOfFuel`, keeping the tape and any output so far
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.data.fuel = fuel;
+    }
+    /// The fuel left from `set_fuel`, or `None` if unlimited
+    pub fn fuel(&self) -> Option<u64> {
+        self.data.fuel
+    }
+    /// The total number of instructions executed so far
+    pub fn instructions(&self) -> u64 {
+        self.data.executed
+    }
+
+    /// The location of the next instruction to execute, or `None` if the program finished
+    pub fn location(&self) -> Option<InsLocation> {
+        (self.data.ins_ptr < self.ops.len()).then_some(InsLocation(self.data.ins_ptr))
+    }
+    pub fn is_finished(&self) -> bool {
+        self.location().is_none()
+    }
+    /// Moves execution to `loc`, where a breakpoint stops execution again. Panics if `loc` is out of bounds
+    pub fn set_location(&mut self, loc: InsLocation) {
+        assert!(loc.0 < self.ops.len());
+        self.data.ins_ptr = loc.0;
+        self.last_stop = None;
+    }
+
+    /// The path of the BFIR token at `loc`, as its index within each nested scope, starting with the outermost.
+    ///
+    /// A loop's condition check before the first iteration is at the path of the `BfIrTok::Loop`,
+    /// and the check after each iteration is one past the last token of the loop body
+    pub fn token_path(&self, loc: InsLocation) -> Vec<usize> {
+        let mut path = vec![];
+        let mut curr = Some(loc.0);
+        while let Some(i) = curr {
+            path.push(self.origins[i].index);
+            curr = self.origins[i].parent;
+        }
+        path.reverse();
+        path
+    }
+    /// The span of the BFIR token at `loc`. Both condition checks of a loop have the span of the whole loop
+    pub fn span(&self, loc: InsLocation) -> Span {
+        self.origins[loc.0].span
+    }
+    /// The inverse of `token_path`
+    pub fn location_of_path(&self, path: &[usize]) -> Option<InsLocation> {
+        (0..self.ops.len())
+            .map(InsLocation)
+            .find(|&loc| self.token_path(loc) == path)
+    }
+
+    /// The locations of the innermost instructions whose span covers `line:col` of `src`, which the program was parsed from,
+    /// for setting breakpoints on source positions. The `[` or `]` of a loop gives both of its condition checks
+    pub fn locations_at(&self, src: &[u8], line: usize, col: usize) -> Vec<InsLocation> {
+        let mut line_starts = std::iter::once(0).chain(
+            src.iter()
+                .enumerate()
+                .filter(|&(_, &c)| c == b'\n')
+                .map(|(i, _)| i + 1),
+        );
+        let (Some(line_start), Some(col)) = (
+            line.checked_sub(1).and_then(|skip| line_starts.nth(skip)),
+            col.checked_sub(1),
+        ) else {
+            return vec![];
+        };
+        // Columns past the end of the line don't wrap around onto the next one
+        let line_len = src[line_start..]
+            .split(|&c| c == b'\n')
+            .next()
+            .map_or(0, <[u8]>::len);
+        if col >= line_len {
+            return vec![];
         }
+        self.locations_at_offset(line_start + col)
+    }
+
+    /// Like `locations_at`, but for the byte at `offset` in the source
+    pub fn locations_at_offset(&self, offset: usize) -> Vec<InsLocation> {
+        let covers = |span: &Span| (span.start..span.end).contains(&offset);
+        let Some(innermost) = self
+            .origins
+            .iter()
+            .filter(|o| covers(&o.span))
+            .map(|o| o.span.end - o.span.start)
+            .min()
+        else {
+            return vec![];
+        };
+
+        (0..self.ops.len())
+            .filter(|&i| {
+                let span = &self.origins[i].span;
+                covers(span) && span.end - span.start == innermost
+            })
+            .map(InsLocation)
+            .collect()
+    }
+
+    /// Starts counting loop iterations and executed instructions, for use by `profile`.
+    /// Resets the counts if profiling was already enabled
+    pub fn enable_profiling(&mut self) {
+        self.counts = Some(OpCounts::new(self.ops.len()));
+    }
+    /// The execution counts of every loop since `enable_profiling`, or `None` if profiling is not enabled
+    pub fn profile(&self) -> Option<LoopProfile> {
+        let counts = self.counts.as_ref()?;
+
+        let loops = self
+            .ops
+            .iter()
+            .enumerate()
+            .filter_map(|(start, op)| {
+                let &Op::LoopStart { end } = op else {
+                    return None;
+                };
+                let path = self.token_path(InsLocation(start));
+                Some(LoopStats {
+                    span: self.origins[start].span,
+                    depth: path.len(),
+                    path,
+                    iterations: counts.iterations[start],
+                    // The ops inside of a loop are the ones between its `LoopStart` and `LoopEnd`
+                    instructions: counts.executed[start + 1..=end].iter().sum(),
+                    cost: counts.cost[start + 1..=end].iter().sum(),
+                    share: 0.,
+                })
+            })
+            .collect();
+
+        Some(LoopProfile::new(
+            counts.executed.iter().sum(),
+            counts.cost.iter().sum(),
+            loops,
+        ))
+    }
+
+    /// Stops `resume` before the instruction at `loc` is executed
+    pub fn add_breakpoint(&mut self, loc: InsLocation) {
+        self.breakpoints.insert(loc.0);
+    }
+    pub fn remove_breakpoint(&mut self, loc: InsLocation) {
+        self.breakpoints.remove(&loc.0);
+    }
+    /// Stops stepping or resuming after an instruction changes `cell`.
+    /// If `value` is given, only stops when `cell` changes to `value`
+    pub fn add_watchpoint(&mut self, cell: usize, value: Option<u8>) {
+        self.watchpoints.push(Watchpoint {
+            cell,
+            value,
+            last: self.data.data[cell].0,
+        });
+    }
+    /// Removes all watchpoints on `cell`
+    pub fn remove_watchpoints(&mut self, cell: usize) {
+        self.watchpoints.retain(|w| w.cell != cell);
     }
 }
 
@@ -123,19 +725,151 @@ where
     IO: ProgramIO,
 {
     pub fn with_stdout<X: Write>(self, w: X) -> Interpreter<impl ProgramIO> {
-        Interpreter::new(self.program, self.data.stdio.with_stdout(w))
+        self.map_stdio(|io| io.with_stdout(w))
     }
     pub fn with_stdin<X: Read>(self, r: X) -> Interpreter<impl ProgramIO> {
-        Interpreter::new(self.program, self.data.stdio.with_stdin(r))
+        self.map_stdio(|io| io.with_stdin(r))
     }
-    pub fn run(&mut self) {
+    /// Swaps out the `ProgramIO`, keeping the compiled program and a fresh tape
+    fn map_stdio<U: ProgramIO>(self, f: impl FnOnce(IO) -> U) -> Interpreter<U> {
+        let fuel = self.data.fuel;
+        let counts = self.counts.map(|_| OpCounts::new(self.ops.len()));
+        Interpreter {
+            program: self.program,
+            ops: self.ops,
+            origins: self.origins,
+            data: RtData {
+                fuel,
+                ..RtData::new(f(self.data.stdio))
+            },
+            breakpoints: self.breakpoints,
+            last_stop: None,
+            watchpoints: self.watchpoints,
+            counts,
+        }
+    }
+    /// Runs the program from the current location until it finishes,
+    /// or until an I/O error occurs or the fuel runs out
+    ///
+    /// Ignores breakpoints and watchpoints. Each `BfIrTok::Debug` writes a `TapeDump` to the program's stderr
+    pub fn run(&mut self) -> Result<RunOutcome, RunError> {
+        self.last_stop = None;
         let start = Instant::now();
-        self.data.run_scope(self.program.clone());
-        println!(";");
-        println!("INTERPRETER_ELAPSED={:?}", start.elapsed());
+        let instructions = match &mut self.counts {
+            Some(counts) => self.data.run_ops(&self.ops, |ins_ptr, new_ins_ptr, cost| {
+                counts.record(&self.ops, ins_ptr, new_ins_ptr, cost)
+            })?,
+            None => self.data.run_ops(&self.ops, |_, _, _| ())?,
+        };
+        Ok(RunOutcome {
+            elapsed: start.elapsed(),
+            instructions,
+        })
     }
     /// Calls `self.run()` and drops `self`, as a helper in case of lifetime issues
-    pub fn run_drop(mut self) {
+    pub fn run_drop(mut self) -> Result<RunOutcome, RunError> {
         self.run()
     }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) -> Result<StopReason, RunError> {
+        self.step_n(1)
+    }
+    /// Executes up to `n` instructions, stopping early at breakpoints and watchpoints
+    ///
+    /// Returns `StopReason::Finished` rather than `Paused` if the program ends
+    pub fn step_n(&mut self, n: u64) -> Result<StopReason, RunError> {
+        self.debug_run(Some(n), false)
+    }
+    /// Executes instructions until a `Read` or `Write` has been executed,
+    /// stopping early at breakpoints and watchpoints
+    pub fn step_until_io(&mut self) -> Result<StopReason, RunError> {
+        self.debug_run(None, true)
+    }
+    /// Executes instructions until the program finishes, or until reaching a breakpoint or watchpoint
+    ///
+    /// Stepping and resuming stop after each `BfIrTok::Debug` with `StopReason::Debug`, instead of writing to stderr
+    pub fn resume(&mut self) -> Result<StopReason, RunError> {
+        self.debug_run(None, false)
+    }
+
+    /// Executes up to `budget` instructions (forever, if `None`), while checking breakpoints and watchpoints.
+    /// If `until_io` is set, stops after executing a `Read` or `Write`
+    ///
+    /// A breakpoint which execution just stopped at is skipped, so that execution can continue from it
+    fn debug_run(
+        &mut self,
+        mut budget: Option<u64>,
+        until_io: bool,
+    ) -> Result<StopReason, RunError> {
+        // The tape may have been edited since the last run
+        for w in &mut self.watchpoints {
+            w.last = self.data.data[w.cell].0;
+        }
+
+        loop {
+            let ins_ptr = self.data.ins_ptr;
+            let Some(ins) = self.ops.get(ins_ptr) else {
+                return Ok(StopReason::Finished);
+            };
+            if self.breakpoints.contains(&ins_ptr) && self.last_stop != Some(ins_ptr) {
+                self.last_stop = Some(ins_ptr);
+                return Ok(StopReason::Breakpoint(InsLocation(ins_ptr)));
+            }
+            if budget == Some(0) {
+                return Ok(StopReason::Paused);
+            }
+            if self.data.fuel == Some(0) {
+                return Err(RunError::OutOfFuel);
+            }
+
+            let mut dump = None;
+            let fuel = self.data.fuel.unwrap_or(u64::MAX);
+            let mut fuel_left = fuel - 1;
+            let res = match ins {
+                Op::Debug { span } => {
+                    dump = Some(self.data.tape_dump(ins_ptr, *span));
+                    Ok(ins_ptr + 1)
+                }
+                ins => self.data.exec(ins, ins_ptr, &mut fuel_left),
+            };
+            if let Some(fuel) = &mut self.data.fuel {
+                // I/O errors don't spend any fuel
+                if !matches!(res, Err(ref e) if e.io_error().is_some()) {
+                    *fuel = fuel_left;
+                }
+            }
+            self.data.ins_ptr = res?;
+            self.data.executed += 1;
+            self.last_stop = None;
+            if let Some(counts) = &mut self.counts {
+                counts.record(&self.ops, ins_ptr, self.data.ins_ptr, fuel - fuel_left);
+            }
+            if let Some(budget) = &mut budget {
+                *budget -= 1;
+            }
+
+            for w in &mut self.watchpoints {
+                let new = self.data.data[w.cell].0;
+                if new == w.last {
+                    continue;
+                }
+                let old = std::mem::replace(&mut w.last, new);
+                if w.value.is_none_or(|v| v == new) {
+                    return Ok(StopReason::Watchpoint {
+                        cell: w.cell,
+                        old,
+                        new,
+                    });
+                }
+            }
+
+            if let Some(dump) = dump {
+                return Ok(StopReason::Debug(dump));
+            }
+            if until_io && matches!(ins, Op::Read | Op::Write) {
+                return Ok(StopReason::Paused);
+            }
+        }
+    }
 }
@@ -1,11 +1,15 @@
+pub mod backend;
 pub mod bf;
 pub mod bf_ffi;
+pub mod bf_fmt;
 pub mod bf_ir;
 pub mod compile_cranelift;
+pub mod compile_object;
 pub mod interpret;
 pub mod io_utils;
 mod math;
 pub mod opt;
+pub mod profile;
 #[cfg(test)]
 pub mod test_suite;
 pub mod wasm2bf;
@@ -1,7 +1,8 @@
 use std::{
     fs::File,
-    io::{empty, sink, Read, Write},
+    io::{empty, sink, stderr, stdin, stdout, Read, Write},
     path::PathBuf,
+    process::ExitCode,
 };
 
 use bf_cranelift::{
@@ -10,27 +11,52 @@ use bf_cranelift::{
     bf_ir::{BfIrScope, BfIrTok},
     interpret::Interpreter,
     io_utils::{self, void, ProgramIO, ReadIter, ReadIterNew},
-    opt::peephole,
+    opt::pass_manager::OptLevel,
 };
 
-fn opt_run(b: impl AsRef<[u8]>, io: impl ProgramIO) {
+fn opt_run(b: impl AsRef<[u8]>, io: impl ProgramIO, opt_level: OptLevel) {
     let program = BfIrScope::parse_sl(b).unwrap();
     println!("parsed!");
     println!("  len={}", program.len());
     println!("  largest_scope={}", program.largest_subscope().len_flat());
 
-    let program = peephole::default_peephole_opt(program);
+    let (program, report) = opt_level.pass_manager().run(program);
 
     println!("optimized!");
+    print!("{report}");
     println!("  len={}", program.len());
     println!("  largest_scope={}", program.largest_subscope().len_flat());
 
     print!("stdout:");
     let mut interp = Interpreter::new(program, io);
-    interp.run();
+    let outcome = interp.run().unwrap();
+    println!(";");
+    println!("INTERPRETER_ELAPSED={:?}", outcome.elapsed);
+    println!("INSTRUCTIONS={}", outcome.instructions);
     println!("=====\n");
 }
 
+/// Runs a BF file, feeding the program stdin.
+/// With `inline_input`, the file is `program!input` instead, and the program is fed its inline input first
+fn run_file(path: &str, opt_level: OptLevel, inline_input: bool) -> Result<(), String> {
+    let src = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let parser = BfParser::new(&src[..]);
+    let (bf, input) = match inline_input {
+        true => parser.parse_with_input(),
+        false => parser.parse().map(|bf| (bf, vec![])),
+    }
+    .map_err(|e| format!("{path}: {e}"))?;
+
+    Interpreter::with_opt_level(
+        BfIrScope::from_bf(bf),
+        io_utils::io_triple((&input[..]).chain(stdin()), stdout(), stderr()),
+        opt_level,
+    )
+    .run_drop()
+    .map_err(|e| format!("{path}: {e}"))?;
+    Ok(())
+}
+
 const TEST_1: &[u8] = include_bytes!("../bf_programs/test_1.bf");
 const AWIB: &[u8] = include_bytes!("../bf_programs/awib-0.4.bf");
 const AWIB_AS_C: &str = "./bf_programs/target/awib-0.4.c";
@@ -38,13 +64,42 @@ const AWIB_TARG: &str = "./bf_programs/target/awib-0.4_target.c";
 
 const EASY_OPT: &[u8] = include_bytes!("../bf_programs/EasyOpt.b");
 
-fn main() {
+/// Usage: `bf_cranelift [-O0|-O1|-O2|-O3] [--inline-input] [FILE]`, where the optimization level defaults to `-O3`.
+/// With `--inline-input`, everything after the first `!` in the file is input for the program
+fn main() -> ExitCode {
+    let mut opt_level = OptLevel::O3;
+    let mut path = None;
+    let mut inline_input = false;
+    for arg in std::env::args().skip(1) {
+        if arg == "--inline-input" {
+            inline_input = true;
+            continue;
+        }
+        match arg.strip_prefix("-O").map(str::parse) {
+            Some(Ok(level)) => opt_level = level,
+            Some(Err(e)) => {
+                eprintln!("bf_cranelift: {e}");
+                return ExitCode::FAILURE;
+            }
+            None => path = Some(arg),
+        }
+    }
+    if let Some(path) = path {
+        return match run_file(&path, opt_level, inline_input) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("bf_cranelift: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     // let p = bf_ir::BfIrScope::parse_sl(TEST_1).unwrap();
     // println!("{p}");
 
     // return;
 
-    opt_run(TEST_1, void());
+    opt_run(TEST_1, void(), opt_level);
     opt_run(
         AWIB,
         io_utils::io_triple(
@@ -52,6 +107,7 @@ fn main() {
             File::create(AWIB_TARG).unwrap(),
             sink(),
         ),
+        opt_level,
     );
     opt_run(
         AWIB,
@@ -60,5 +116,7 @@ fn main() {
             File::create(AWIB_AS_C).unwrap(),
             sink(),
         ),
+        opt_level,
     );
+    ExitCode::SUCCESS
 }
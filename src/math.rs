@@ -1 +1,20 @@
+/// The multiplicative inverse of `x` modulo `256`, which only exists for odd `x`
+pub(crate) fn inverse_mod_256(x: u8) -> Option<u8> {
+    if x & 1 == 0 {
+        return None;
+    }
+    // Newton's method doubles the number of correct low bits every step, and `x` is its own inverse mod `8`
+    let mut inv = x;
+    for _ in 0..2 {
+        inv = inv.wrapping_mul(2u8.wrapping_sub(x.wrapping_mul(inv)));
+    }
+    Some(inv)
+}
 
+/// The greatest common divisor of `a` and `b`, where `gcd(x, 0)` is `x`
+pub(crate) fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
@@ -0,0 +1,56 @@
+//! Passes driven by an analysis of the whole program, rather than a window of tokens
+
+use crate::{
+    bf_ir::{BfIrScope, BfIrTok},
+    opt::pass_manager::ProgramPass,
+};
+
+/// Removes tokens which can never have an effect because the current cell is known to be zero when they run,
+/// such as the second loop of `[-][>]`
+///
+/// A cell is only known to be zero after a token which leaves it zero, so this holds for any starting tape
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeadCodeElim;
+
+impl DeadCodeElim {
+    /// Whether the current cell is still known to be zero after `tok` runs, given whether it was before.
+    /// Tokens which are removed are not passed in
+    fn zero_after(tok: &BfIrTok, zero: bool) -> bool {
+        match tok {
+            BfIrTok::Loop(_) | BfIrTok::MulAdd(_) | BfIrTok::Scan(_) | BfIrTok::Set(0) => true,
+            BfIrTok::Modify { adds, ptr_delta } => zero && *ptr_delta == 0 && adds.get(0).0 == 0,
+            BfIrTok::Write | BfIrTok::Debug => zero,
+            BfIrTok::Set(_) | BfIrTok::Read => false,
+        }
+    }
+}
+
+impl ProgramPass for DeadCodeElim {
+    fn run(&mut self, program: BfIrScope) -> (BfIrScope, usize) {
+        let mut removed = 0;
+
+        // The state of each scope is whether the current cell is known to be zero.
+        // Loop bodies may run again after themselves, so nothing is known when they start
+        let program = BfIrScope::rebuild(program, |sc, i, out, zero: &mut bool| {
+            let tok = &sc[i];
+            let dead = *zero
+                && matches!(
+                    tok,
+                    BfIrTok::Loop(_) | BfIrTok::MulAdd(_) | BfIrTok::Scan(_) | BfIrTok::Set(0)
+                );
+            if dead {
+                removed += 1;
+                return None;
+            }
+            *zero = Self::zero_after(tok, *zero);
+            match tok {
+                BfIrTok::Loop(body) => Some(body.clone()),
+                tok => {
+                    out.push(tok.clone(), sc.spans()[i]);
+                    None
+                }
+            }
+        });
+        (program, removed)
+    }
+}
@@ -1 +1,3 @@
 pub mod peephole;
+pub mod pass_manager;
+pub mod analysis;
@@ -0,0 +1,295 @@
+//! Runs a pipeline of optimization passes, collecting statistics about each pass
+
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    bf_ir::{BfIrScope, BfIrTok},
+    opt::{
+        analysis::DeadCodeElim,
+        peephole::{
+            self, DataAddFold, DataAddSetFold, LoopScan, LoopSet0, MulAddLoop, PeepholeApply,
+            PeepholePass, PtrAddFold,
+        },
+    },
+};
+
+/// A preset pipeline of passes, from doing nothing to running every pass until nothing changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum OptLevel {
+    /// No passes, so programs stay exactly as `BfIrScope::from_bf` built them
+    #[default]
+    O0,
+    /// Only folds adjacent `Modify`s and removes the ones with no effect
+    O1,
+    /// Also rewrites loop idioms into `Set`, `MulAdd` and `Scan`, running every pass once
+    O2,
+    /// Also removes code which can never run, using `analysis::DeadCodeElim`,
+    /// and repeats every pass until nothing changes
+    O3,
+}
+
+impl OptLevel {
+    pub const ALL: [OptLevel; 4] = [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3];
+
+    /// The pipeline for this level
+    pub fn pass_manager(self) -> PassManager {
+        let folds = PassManager::new()
+            .with_pass(DataAddFold)
+            .with_pass(PtrAddFold);
+        let idioms = |pm: PassManager| {
+            pm.with_pass(LoopSet0)
+                .with_pass(MulAddLoop)
+                .with_pass(LoopScan)
+                .with_pass(DataAddSetFold)
+        };
+        match self {
+            OptLevel::O0 => PassManager::new(),
+            OptLevel::O1 => folds,
+            OptLevel::O2 => idioms(folds),
+            OptLevel::O3 => idioms(folds)
+                .with_program_pass(DeadCodeElim)
+                .until_fixpoint(16),
+        }
+    }
+
+    /// Runs the pipeline for this level over `program`
+    pub fn optimize(self, program: BfIrScope) -> BfIrScope {
+        match self {
+            OptLevel::O0 => program,
+            _ => self.pass_manager().run(program).0,
+        }
+    }
+}
+
+impl Display for OptLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    /// Parses `0` to `3`, with or without a leading `O`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('O').unwrap_or(s) {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            _ => Err(format!("unknown optimization level `{s}`, expected 0 to 3")),
+        }
+    }
+}
+
+/// Statistics for a single run of a pass over the whole program
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PassStats {
+    /// The name of the pass, from `PeepholePass::name`
+    pub name: String,
+    /// The iteration of the pipeline which the pass ran in, starting at `0`
+    pub iteration: usize,
+    /// The number of times the pass replaced tokens
+    pub rewrites: usize,
+    /// The change in the recursive token count of the program, as in `BfIrScope::len`
+    pub token_delta: isize,
+    pub elapsed: Duration,
+}
+
+/// The results of `PassManager::run`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OptReport {
+    /// Every run of every pass, in the order they ran
+    pub passes: Vec<PassStats>,
+    /// The number of times the pipeline ran
+    pub iterations: usize,
+    /// Whether the last iteration made no rewrites, so that running the pipeline again would change nothing
+    pub converged: bool,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+    pub elapsed: Duration,
+}
+
+impl OptReport {
+    /// The total number of rewrites made by all runs of the pass named `name`
+    pub fn rewrites_by(&self, name: &str) -> usize {
+        self.passes
+            .iter()
+            .filter(|p| p.name == name)
+            .map(|p| p.rewrites)
+            .sum()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl Display for OptReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "tokens: {} -> {} in {:?}, {} iteration(s){}",
+            self.tokens_before,
+            self.tokens_after,
+            self.elapsed,
+            self.iterations,
+            if self.converged { ", converged" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "{:>5}  {:<16} {:>10} {:>12} {:>12}",
+            "iter", "pass", "rewrites", "token delta", "elapsed"
+        )?;
+        for p in &self.passes {
+            writeln!(
+                f,
+                "{:>5}  {:<16} {:>10} {:>12} {:>12}",
+                p.iteration,
+                p.name,
+                p.rewrites,
+                p.token_delta,
+                format!("{:?}", p.elapsed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the replacements made by the wrapped pass
+struct Counted<'a> {
+    pass: &'a mut dyn PeepholePass,
+    rewrites: usize,
+}
+
+impl PeepholePass for Counted<'_> {
+    fn min_tokens(&self) -> usize {
+        self.pass.min_tokens()
+    }
+
+    fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
+        let res = self.pass.apply(instructions);
+        if let PeepholeApply::Replace { .. } = res {
+            self.rewrites += 1;
+        }
+        res
+    }
+
+    fn name(&self) -> &str {
+        self.pass.name()
+    }
+}
+
+/// A pass which rewrites the whole program at once, such as one driven by an analysis in `opt::analysis`
+pub trait ProgramPass {
+    /// Rewrites `program`, returning the new program and the number of rewrites made
+    fn run(&mut self, program: BfIrScope) -> (BfIrScope, usize);
+
+    /// The name shown in reports, like `PeepholePass::name`
+    fn name(&self) -> &str {
+        peephole::default_pass_name::<Self>()
+    }
+}
+
+/// Runs a peephole pass on every window of tokens in the program
+struct Peephole(Box<dyn PeepholePass>);
+
+impl ProgramPass for Peephole {
+    fn run(&mut self, program: BfIrScope) -> (BfIrScope, usize) {
+        let mut counted = Counted {
+            pass: self.0.as_mut(),
+            rewrites: 0,
+        };
+        let program = peephole::apply_pass(program, &mut counted);
+        (program, counted.rewrites)
+    }
+
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+}
+
+/// A configurable pipeline of passes, which are run in order over the whole program
+pub struct PassManager {
+    passes: Vec<Box<dyn ProgramPass>>,
+    max_iterations: usize,
+}
+
+impl Default for PassManager {
+    /// An empty pipeline, like `new`
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PassManager {
+    /// An empty pipeline, which runs once
+    pub fn new() -> Self {
+        Self {
+            passes: vec![],
+            max_iterations: 1,
+        }
+    }
+    /// Adds the peephole pass `pass` to the end of the pipeline
+    pub fn with_pass(mut self, pass: impl PeepholePass + 'static) -> Self {
+        self.passes.push(Box::new(Peephole(Box::new(pass))));
+        self
+    }
+    /// Adds `pass`, which rewrites the whole program at once, to the end of the pipeline
+    pub fn with_program_pass(mut self, pass: impl ProgramPass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+    /// Repeats the pipeline until an iteration makes no rewrites, running it at most `max_iterations` times
+    pub fn until_fixpoint(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations.max(1);
+        self
+    }
+
+    /// Runs the pipeline over `program`
+    pub fn run(&mut self, mut program: BfIrScope) -> (BfIrScope, OptReport) {
+        let start = Instant::now();
+        let tokens_before = program.len();
+        let mut passes = vec![];
+        let mut iterations = 0;
+        let mut converged = false;
+
+        while iterations < self.max_iterations && !converged {
+            let mut iteration_rewrites = 0;
+            for pass in &mut self.passes {
+                let pass_start = Instant::now();
+                let len_before = program.len();
+
+                let rewrites;
+                (program, rewrites) = pass.run(program);
+
+                iteration_rewrites += rewrites;
+                passes.push(PassStats {
+                    name: pass.name().to_string(),
+                    iteration: iterations,
+                    rewrites,
+                    token_delta: program.len() as isize - len_before as isize,
+                    elapsed: pass_start.elapsed(),
+                });
+            }
+            iterations += 1;
+            converged = iteration_rewrites == 0;
+        }
+
+        let report = OptReport {
+            passes,
+            iterations,
+            converged,
+            tokens_before,
+            tokens_after: program.len(),
+            elapsed: start.elapsed(),
+        };
+        (program, report)
+    }
+}
@@ -1,13 +1,16 @@
-use std::{any::type_name, time::Instant};
+use std::{any::type_name, num::Wrapping};
 
 use crate::{
-    bf::BfTok,
-    bf_ir::{BfIrScope, BfIrTok},
+    bf::{BfTok, Span},
+    bf_ir::{Adds, BfIrScope, BfIrTok},
+    math,
+    opt::pass_manager::OptLevel,
 };
 
 pub enum PeepholeApply {
     /// Replace the first `count` instructions with `new`
     ///
+    /// Each new instruction gets the joined span of all the replaced instructions.
     /// If there are not `count` instructions remaining, this may cause a panic
     Replace { count: usize, new: Vec<BfIrTok> },
     /// Do not replace any instructions
@@ -25,8 +28,16 @@ pub trait PeepholePass {
     }
 
     fn apply<'a, 'b>(&'a mut self, instructions: &'b [BfIrTok]) -> PeepholeApply;
+
+    /// The name shown in reports, such as `OptReport`
+    ///
+    /// By default, this is the name of the type without its module path
+    fn name(&self) -> &str {
+        default_pass_name::<Self>()
+    }
 }
 
+/// Folds adjacent `Modify`s into one, shifting the offsets of the second by the `ptr_delta` of the first
 pub struct DataAddFold;
 
 impl PeepholePass for DataAddFold {
@@ -37,86 +48,145 @@ impl PeepholePass for DataAddFold {
 
     #[inline]
     fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
-        return todo!();
-        #[cfg(not)]
         match instructions {
-            [BfIrTok::Add(a), BfIrTok::Add(b), ..] => PeepholeApply::Replace {
-                count: 2,
-                new: vec![BfIrTok::Add(a + b)],
-            },
+            [BfIrTok::Modify {
+                adds: a,
+                ptr_delta: a_delta,
+            }, BfIrTok::Modify {
+                adds: b,
+                ptr_delta: b_delta,
+            }, ..] => {
+                let mut adds = a.clone();
+                for &(offset, delta) in b {
+                    adds.add(offset + a_delta, delta);
+                }
+                PeepholeApply::Replace {
+                    count: 2,
+                    new: vec![BfIrTok::Modify {
+                        adds,
+                        ptr_delta: a_delta + b_delta,
+                    }],
+                }
+            }
             _ => PeepholeApply::Pass,
         }
     }
 }
 
+/// Removes `Modify`s which have no effect, such as `<>` or `+-`
+///
+/// Pointer moves are already folded together with the adds around them, by `BfIrScope::from_bf` and `DataAddFold`
 pub struct PtrAddFold;
 
 impl PeepholePass for PtrAddFold {
-    #[inline]
-    fn min_tokens(&self) -> usize {
-        2
-    }
-
     #[inline]
     fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
-        return todo!();
-        #[cfg(not)]
-        // As far as measured, there's not much of a performance gain to folding more than 2 at once
         match instructions {
-            [BfIrTok::PtrAdd(a), BfIrTok::PtrAdd(b), ..] => PeepholeApply::Replace {
-                count: 2,
-                new: vec![BfIrTok::PtrAdd(
-                    // Wrapping should never be needed
-                    *a + *b,
-                )],
-            },
+            [BfIrTok::Modify { adds, ptr_delta: 0 }, ..] if adds.is_empty() => {
+                PeepholeApply::Replace {
+                    count: 1,
+                    new: vec![],
+                }
+            }
             _ => PeepholeApply::Pass,
         }
     }
 }
 
-/// Maps `[-]` to `set 0`
+/// Maps clear loops such as `[-]`, `[+]` or `[---]` to `Set(0)`
 pub struct LoopSet0;
 
 impl PeepholePass for LoopSet0 {
     #[inline]
-    fn apply<'a, 'b>(&'a mut self, instructions: &'b [BfIrTok]) -> PeepholeApply {
-        return todo!();
-        #[cfg(not)]
-        {
-            let [BfIrTok::Loop(inner), ..] = instructions else {
-                return PeepholeApply::Pass;
-            };
+    fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
+        let [BfIrTok::Loop(inner), ..] = instructions else {
+            return PeepholeApply::Pass;
+        };
+
+        // Only allowed if this is the only token in the scope
+        let [BfIrTok::Modify { adds, ptr_delta: 0 }] = &inner[..] else {
+            return PeepholeApply::Pass;
+        };
+        let &[(0, delta)] = &adds[..] else {
+            return PeepholeApply::Pass;
+        };
+
+        // The cell reaches `0` from every value only if `delta` shares no factor with `256`, so only odd steps terminate.
+        // With an even step, the loop runs forever for some values, which must be kept
+        if delta.0 % 2 != 0 {
+            PeepholeApply::Replace {
+                count: 1,
+                new: vec![BfIrTok::Set(0)],
+            }
+        } else {
+            PeepholeApply::Pass
+        }
+    }
+}
 
-            // Only allowed if this is the only token in the scope
-            let [BfIrTok::Add(delta)] = &inner[..] else {
-                return PeepholeApply::Pass;
-            };
+/// Maps balanced loops which move the current cell into others, such as `[->+>++<<]`, to `MulAdd`
+///
+/// The loop body must be a single `Modify` with no pointer movement and an odd step on the current cell,
+/// so that the number of iterations is known from the value of the cell
+pub struct MulAddLoop;
 
-            // Does cell wrap from any value to `0` eventually, after enough additions (otherwise this could go on forever)
-            // In other words, does `delta` share a factor with `256` (is `delta` a multiple of `2`)
+impl PeepholePass for MulAddLoop {
+    #[inline]
+    fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
+        let [BfIrTok::Loop(inner), ..] = instructions else {
+            return PeepholeApply::Pass;
+        };
+        let [BfIrTok::Modify { adds, ptr_delta: 0 }] = &inner[..] else {
+            return PeepholeApply::Pass;
+        };
+        // Loops which only change the current cell are left to `LoopSet0`
+        if adds.len() < 2 {
+            return PeepholeApply::Pass;
+        }
+        // With a step of `s`, a cell with value `v` takes `-v / s` iterations to reach `0`
+        let Some(inv) = math::inverse_mod_256(adds.get(0).0.wrapping_neg() as u8) else {
+            return PeepholeApply::Pass;
+        };
+
+        let factors = adds
+            .iter()
+            .filter(|&&(offset, _)| offset != 0)
+            .map(|&(offset, delta)| (offset, Wrapping((delta.0 as u8).wrapping_mul(inv) as i8)))
+            .collect();
+        PeepholeApply::Replace {
+            count: 1,
+            new: vec![BfIrTok::MulAdd(factors)],
+        }
+    }
+}
 
-            let does_terminate = match delta.0 {
-                0 => false,
-                1 => true,
-                n => n % 2 == 0,
-            };
+/// Maps loops which only move the pointer, such as `[>]` or `[<<]`, to `Scan`
+pub struct LoopScan;
 
-            if does_terminate {
+impl PeepholePass for LoopScan {
+    #[inline]
+    fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
+        let [BfIrTok::Loop(inner), ..] = instructions else {
+            return PeepholeApply::Pass;
+        };
+        match &inner[..] {
+            [BfIrTok::Modify { adds, ptr_delta }] if adds.is_empty() && *ptr_delta != 0 => {
                 PeepholeApply::Replace {
                     count: 1,
-                    new: vec![BfIrTok::Set(0)],
+                    new: vec![BfIrTok::Scan(*ptr_delta)],
                 }
-            } else {
-                PeepholeApply::Pass
             }
+            _ => PeepholeApply::Pass,
         }
     }
 }
 
-/// Folds together adjacent `Set(x)` and `Add(y)` appropriately:
-/// * `Set(x), Add(y)` => `Set(x + y)`
-/// * `Add(x), Set(y)` => `Set(y)`
+/// Folds together adjacent `Set`s and the adds which touch the same cell:
+/// * `Set(x), Modify` => `Set(x + add@0), Modify` (without its add at offset `0`)
+/// * `Modify, Set(y)` => `Modify` (without its add at `ptr_delta`), `Set(y)`
+/// * `Set(x), Set(y)` => `Set(y)`
+///
+/// `Modify`s which are left with no effect are removed
 pub struct DataAddSetFold;
 
 impl PeepholePass for DataAddSetFold {
@@ -124,39 +194,64 @@ impl PeepholePass for DataAddSetFold {
         2
     }
     #[inline]
-    fn apply<'a, 'b>(&'a mut self, instructions: &'b [BfIrTok]) -> PeepholeApply {
-        return todo!();
-        #[cfg(not)]
+    fn apply(&mut self, instructions: &[BfIrTok]) -> PeepholeApply {
+        /// Removes the add at `offset`, keeping the `Modify` only if it still has an effect
+        fn without_add(adds: &Adds, offset: isize, ptr_delta: isize) -> Option<BfIrTok> {
+            let adds: Adds = adds.iter().copied().filter(|&(o, _)| o != offset).collect();
+            (!adds.is_empty() || ptr_delta != 0).then_some(BfIrTok::Modify { adds, ptr_delta })
+        }
+
         match instructions {
-            [BfIrTok::Add(_discard), BfIrTok::Set(y), ..] => PeepholeApply::Replace {
+            [BfIrTok::Set(x), BfIrTok::Modify { adds, ptr_delta }, ..] if adds.get(0).0 != 0 => {
+                let set = BfIrTok::Set(x.wrapping_add_signed(adds.get(0).0));
+                PeepholeApply::Replace {
+                    count: 2,
+                    new: [Some(set), without_add(adds, 0, *ptr_delta)]
+                        .into_iter()
+                        .flatten()
+                        .collect(),
+                }
+            }
+            [BfIrTok::Modify { adds, ptr_delta }, BfIrTok::Set(y), ..]
+                if adds.get(*ptr_delta).0 != 0 =>
+            {
+                PeepholeApply::Replace {
+                    count: 2,
+                    new: [
+                        without_add(adds, *ptr_delta, *ptr_delta),
+                        Some(BfIrTok::Set(*y)),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                }
+            }
+            [BfIrTok::Set(_), BfIrTok::Set(y), ..] => PeepholeApply::Replace {
                 count: 2,
                 new: vec![BfIrTok::Set(*y)],
             },
-            [BfIrTok::Set(x), BfIrTok::Add(y), ..] => PeepholeApply::Replace {
-                count: 2,
-                new: vec![BfIrTok::Set(x.wrapping_add_signed(y.0))],
-            },
             _ => PeepholeApply::Pass,
         }
     }
 }
 
+/// The name of the pass type `P` without its module path, which passes are named by default in reports
+pub(crate) fn default_pass_name<P: ?Sized>() -> &'static str {
+    let name = type_name::<P>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
 /// Apply a peephole pass on every window of tokens in the program
-fn apply_pass<P>(mut toks: BfIrScope, pass: &mut P) -> BfIrScope
+pub(crate) fn apply_pass<P>(toks: BfIrScope, pass: &mut P) -> BfIrScope
 where
     P: PeepholePass,
 {
-    let mut i = 0;
-    loop {
-        if i >= toks.len() {
-            return toks;
-        }
-
+    BfIrScope::rebuild(toks, |toks, i, out, _: &mut ()| {
         // Apply repeatedly on this index until no more changes are made
         loop {
-            let remaining_toks = toks.len() - i;
+            let remaining_toks = toks.len_flat() - i;
             if remaining_toks < pass.min_tokens() {
-                return toks;
+                break;
             }
 
             let Some(PeepholeApply::Replace { count, new }) =
@@ -170,16 +265,22 @@ where
             if count > remaining_toks {
                 println!(
                     "WARNING: `apply_pass` of `{}` returned a count of {count} with tokens: `len={},{:?}`",
-                    type_name::<P>(),
+                    pass.name(),
                     remaining_toks,
                     &toks[i..]
                 );
                 break;
             }
 
-            toks = toks.modify(|v| {
+            *toks = toks.clone().modify(|v, spans| {
                 let old = v.clone();
 
+                let span = spans[i..i + count]
+                    .iter()
+                    .copied()
+                    .reduce(Span::join)
+                    .unwrap_or(spans[i]);
+                spans.splice(i..i + count, vec![span; new.len()]);
                 let _replaced_elems = v.splice(i..i + count, new).collect::<Vec<_>>();
 
                 if false {
@@ -192,44 +293,18 @@ where
             });
         }
 
-        // We want to allow running a peephole opt starting from and going across a loop. But, we also recursively apply the optimization
-        if let Some(BfIrTok::Loop(inner)) = toks.get(i) {
-            let new_loop = BfIrTok::Loop(apply_pass(inner.clone(), pass));
-            toks = toks.modify(|v| v[i] = new_loop);
+        // We want to allow running a peephole opt starting from and going across a loop. But, we also apply the optimization inside of the loop
+        match toks.get(i)? {
+            BfIrTok::Loop(inner) => Some(inner.clone()),
+            tok => {
+                out.push(tok.clone(), toks.spans()[i]);
+                None
+            }
         }
-
-        i += 1;
-    }
-}
-
-/// Apply a peephole pass on every window of tokens in the program
-///
-/// Prints performance information
-fn apply_pass_bench<P>(toks: BfIrScope, pass: &mut P) -> BfIrScope
-where
-    P: PeepholePass,
-{
-    let start = Instant::now();
-
-    let sc = apply_pass(toks, pass);
-
-    println!("PASS={}", type_name::<P>());
-    println!("  ELAPSED={:?}", start.elapsed());
-
-    sc
+    })
 }
 
+/// Runs the `OptLevel::O3` pipeline, repeating every pass until nothing changes
 pub fn default_peephole_opt(toks: BfIrScope) -> BfIrScope {
-    return toks;
-
-    let start = Instant::now();
-
-    let toks = apply_pass_bench(toks, &mut DataAddFold);
-    let toks = apply_pass_bench(toks, &mut PtrAddFold);
-    let toks = apply_pass_bench(toks, &mut LoopSet0);
-    let toks = apply_pass_bench(toks, &mut DataAddSetFold);
-
-    println!("OPT_ELAPSED={:?}", start.elapsed());
-
-    toks
+    OptLevel::O3.pass_manager().run(toks).0
 }
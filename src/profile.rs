@@ -0,0 +1,95 @@
+//! Loop-level execution profiles, collected by `interpret::Interpreter::enable_profiling`
+
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::bf::Span;
+
+/// Execution counts for a single `BfIrTok::Loop`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoopStats {
+    /// The source of the loop, from its opening to its closing bracket
+    pub span: Span,
+    /// The path of the loop in the profiled `BfIrScope`, as in `Interpreter::token_path`
+    pub path: Vec<usize>,
+    /// The number of loops this loop is nested in, including itself. A top-level loop has a depth of `1`
+    pub depth: usize,
+    /// The number of times the loop body was entered
+    pub iterations: u64,
+    /// The number of instructions executed inside the loop, including nested loops
+    pub instructions: u64,
+    /// `instructions` plus the steps taken by each `Scan` inside the loop, as spent from the interpreter's fuel
+    pub cost: u64,
+    /// `cost` as a fraction of the cost of the whole program, which approximates the loop's share of the run time
+    pub share: f64,
+}
+
+/// Execution counts for every loop of a program, from the hottest loop to the coldest
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoopProfile {
+    /// The total number of instructions executed by the program
+    pub instructions: u64,
+    /// The total cost of the program, counted like `LoopStats::cost`
+    pub cost: u64,
+    /// Sorted by `cost`, descending
+    pub loops: Vec<LoopStats>,
+}
+
+impl LoopProfile {
+    /// Sorts `loops` from hottest to coldest, and fills in their `share`
+    pub(crate) fn new(instructions: u64, cost: u64, mut loops: Vec<LoopStats>) -> Self {
+        for l in &mut loops {
+            l.share = match cost {
+                0 => 0.,
+                total => l.cost as f64 / total as f64,
+            };
+        }
+        // Ties keep the program order
+        loops.sort_by_key(|l| std::cmp::Reverse(l.cost));
+        Self {
+            instructions,
+            cost,
+            loops,
+        }
+    }
+
+    /// The `n` hottest loops
+    pub fn hottest(&self, n: usize) -> &[LoopStats] {
+        &self.loops[..n.min(self.loops.len())]
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl Display for LoopProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "total instructions: {}, total cost: {}",
+            self.instructions, self.cost
+        )?;
+        writeln!(
+            f,
+            "{:>8} {:>14} {:>14} {:>12} {:>6}  source",
+            "share", "cost", "instructions", "iterations", "depth"
+        )?;
+        for l in &self.loops {
+            writeln!(
+                f,
+                "{:>7.2}% {:>14} {:>14} {:>12} {:>6}  {} (bytes {}..{})",
+                l.share * 100.,
+                l.cost,
+                l.instructions,
+                l.iterations,
+                l.depth,
+                l.span,
+                l.span.start,
+                l.span.end
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -2,16 +2,33 @@ use std::{
     collections::{HashMap, VecDeque},
     ffi::{OsStr, OsString},
     fs,
-    io::{empty, Read},
+    hash::{Hash, Hasher},
+    io::{empty, Read, Write},
+    num::Wrapping,
     path::{Path, PathBuf},
+    process::{Command, Stdio},
     str::FromStr,
 };
 
+use cranelift::prelude::settings;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::default_libcall_names;
+
 use crate::{
-    bf::BfParser,
-    bf_ir::BfIrScope,
-    interpret::Interpreter,
-    io_utils::{self, ReadIter, ReadIterNew},
+    backend::{Backend, InterpreterBackend, JitBackend, RunResult},
+    bf::{BfParser, BfTok, ParseError, Span},
+    bf_fmt::{BfSource, FmtStyle},
+    bf_ir::{Adds, BfIrScope, BfIrTok},
+    compile_cranelift::JitExit,
+    compile_cranelift::{self, CodegenConfig, CodegenOptLevel, JitProgram, JitState},
+    compile_object,
+    interpret::{InsLocation, Interpreter, RunError, StopReason},
+    io_utils::{self, EOIPanic, ProgramIO, ReadIter, ReadIterNew},
+    opt::{
+        analysis::DeadCodeElim,
+        pass_manager::{OptLevel, PassManager, ProgramPass},
+        peephole::{self, DataAddFold, LoopSet0, PeepholeApply, PeepholePass, PtrAddFold},
+    },
 };
 
 /// Parses bytes as a path and only returns the path if a file exists at the path
@@ -37,24 +54,126 @@ pub struct TestCase {
 }
 
 impl TestCase {
+    /// A test case for a self-contained `program!input` source, as parsed by `BfParser::parse_with_input`
+    pub fn from_inline(src: &[u8], desired_out: impl Into<Vec<u8>>) -> Self {
+        let (_, input) = BfParser::new(src).parse_with_input().unwrap();
+        // Keeps the `!`, which is a comment when parsing the program alone
+        let program = src[..src.len() - input.len()].to_vec();
+        Self {
+            program,
+            input,
+            desired_out: desired_out.into(),
+        }
+    }
+
+    /// Runs the program on every backend, both unoptimized and optimized,
+    /// checking that they emit the desired output and agree on the final tape
     #[track_caller]
     pub fn test(&self) {
         let program = BfIrScope::parse_sl(&self.program).unwrap();
+        let optimized = peephole::default_peephole_opt(program.clone());
 
-        let mut stdout = Vec::new();
-        let _res = Interpreter::new(
-            program,
-            io_utils::io_triple(
-                ReadIter::new(self.input.iter().copied()),
-                &mut stdout,
-                empty(),
+        let interp = self.test_backend(&mut InterpreterBackend::default(), program.clone());
+        let runs = [
+            (
+                "JIT",
+                self.test_backend(&mut JitBackend::default(), program),
             ),
-        )
-        .run_drop();
+            (
+                "optimized interpreter",
+                self.test_backend(&mut InterpreterBackend::default(), optimized.clone()),
+            ),
+            (
+                "optimized JIT",
+                self.test_backend(&mut JitBackend::default(), optimized),
+            ),
+        ];
+
+        for (name, res) in runs {
+            assert_eq!(
+                interp.data_ptr, res.data_ptr,
+                "The interpreter (left) and {name} (right) finished on different cells"
+            );
+            if let Some(i) = (0..interp.tape.len()).find(|&i| interp.tape[i] != res.tape[i]) {
+                panic!(
+                    "The interpreter ({}) and {name} ({}) disagree on cell {i}",
+                    interp.tape[i], res.tape[i]
+                );
+            }
+        }
+    }
 
+    /// Runs the program on `backend`, checking that it emits the desired output
+    #[track_caller]
+    pub fn test_backend(&self, backend: &mut impl Backend, program: BfIrScope) -> RunResult {
+        let mut stdout = Vec::new();
+        let res = backend
+            .run(
+                program,
+                io_utils::io_triple(
+                    ReadIter::new(self.input.iter().copied()),
+                    &mut stdout,
+                    empty(),
+                ),
+            )
+            .unwrap();
+
+        assert!(
+            res.io_error.is_none(),
+            "The `{}` backend hit an I/O error: {:?}",
+            backend.name(),
+            res.io_error
+        );
+        assert!(
+            !res.out_of_fuel && !res.never_halts,
+            "The `{}` backend stopped before the program finished",
+            backend.name()
+        );
         assert_eq!(
-            self.desired_out, stdout,
-            "Desired output (left) was not emitted by this test (right)"
+            self.desired_out,
+            stdout,
+            "Desired output (left) was not emitted by the `{}` backend (right)",
+            backend.name()
+        );
+
+        res
+    }
+
+    /// Compiles the program into an executable with the system C toolchain, inside of a new temporary directory
+    ///
+    /// Returns the path of the executable
+    pub fn build_aot(&self, name: &str) -> PathBuf {
+        let program = BfIrScope::parse_sl(&self.program).unwrap();
+        let obj = compile_object::compile_object(program, &CodegenConfig::default()).unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("bf_cranelift_aot_{}_{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let obj_path = dir.join(format!("{name}.o"));
+        let exe_path = dir.join(name);
+        fs::write(&obj_path, obj).unwrap();
+        compile_object::link_executable(&obj_path, &exe_path).unwrap();
+        exe_path
+    }
+
+    /// Compiles the program into an executable with the system C toolchain, and checks its output
+    #[track_caller]
+    pub fn test_aot(&self, name: &str) {
+        let exe_path = self.build_aot(name);
+
+        let mut child = Command::new(&exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&self.input).unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        fs::remove_dir_all(exe_path.parent().unwrap()).unwrap();
+
+        assert_eq!(
+            self.desired_out, out.stdout,
+            "Desired output (left) was not emitted by the AOT executable (right)"
         );
     }
 }
@@ -69,6 +188,1109 @@ fn test_1_bf() {
     .test()
 }
 
+#[test]
+fn aot_test_1_bf() {
+    TestCase {
+        program: include_bytes!("../bf_programs/test_1.bf").to_vec(),
+        input: vec![],
+        desired_out: b"\0Hello World! 255\n".to_vec(),
+    }
+    .test_aot("test_1")
+}
+
+// Uses `/dev/full`, which only Linux has
+#[cfg(target_os = "linux")]
+#[test]
+fn aot_write_error() {
+    // Writes forever, until `putchar` fails
+    let case = TestCase {
+        program: b"+[.]".to_vec(),
+        input: vec![],
+        desired_out: vec![],
+    };
+    let exe_path = case.build_aot("write_error");
+
+    let status = Command::new(&exe_path)
+        .stdout(fs::File::create("/dev/full").unwrap())
+        .status()
+        .unwrap();
+    fs::remove_dir_all(exe_path.parent().unwrap()).unwrap();
+    assert_eq!(status.code(), Some(compile_cranelift::EXIT_IO_ERROR));
+}
+
+#[test]
+fn aot_awib() {
+    let program = include_bytes!("../bf_programs/awib-0.4.bf").to_vec();
+    let input = include_bytes!("../bf_programs/test_1.bf").to_vec();
+
+    let mut desired_out = Vec::new();
+    Interpreter::new(
+        BfIrScope::parse_sl(&program).unwrap(),
+        io_utils::io_triple(&input[..], &mut desired_out, empty()),
+    )
+    .run_drop()
+    .unwrap();
+
+    TestCase {
+        program,
+        input,
+        desired_out,
+    }
+    .test_aot("awib")
+}
+
+#[test]
+fn scan_loops() {
+    let opt = |src: &str| peephole::default_peephole_opt(BfIrScope::parse_sl(src).unwrap());
+    assert_eq!(opt("[>]").to_string(), "[>1]");
+    assert_eq!(opt("[<<<]").to_string(), "[>-3]");
+    assert!(matches!(opt("[>+<]")[..], [BfIrTok::Loop(_)]));
+
+    let ones = |n: usize| "+>".repeat(n);
+    let cases = [
+        // Long enough for several vectorized steps, in both directions
+        [&ones(40), &"<".repeat(40), "[>]", "-."].concat(),
+        [">", &ones(40), "<", "[<]", "-."].concat(),
+        // Strides which skip over zero cells
+        ["+>>>+>>>+>>+>>>>>>", &"<".repeat(18), "[>>>]", "-."].concat(),
+        ["+>>", &ones(30), "<<<[<<<]", "-."].concat(),
+        // Across both ends of the tape
+        ["<<<<<", &ones(7), &"<".repeat(7), "[>]", "-."].concat(),
+        [&ones(7), &"<".repeat(7), "[<]", "-."].concat(),
+    ];
+    for program in cases {
+        TestCase {
+            program: program.into_bytes(),
+            input: vec![],
+            desired_out: vec![255],
+        }
+        .test();
+    }
+}
+
+#[test]
+fn optimized_awib() {
+    let program = include_bytes!("../bf_programs/awib-0.4.bf").to_vec();
+    let input = include_bytes!("../bf_programs/test_1.bf").to_vec();
+
+    let mut desired_out = Vec::new();
+    Interpreter::new(
+        BfIrScope::parse_sl(&program).unwrap(),
+        io_utils::io_triple(&input[..], &mut desired_out, empty()),
+    )
+    .run_drop()
+    .unwrap();
+
+    // The optimized program lowered back into BF behaves the same too
+    let optimized = peephole::default_peephole_opt(BfIrScope::parse_sl(&program).unwrap());
+    let lowered = BfSource::from_ir(&optimized, FmtStyle::Minified).to_string();
+
+    for program in [program, lowered.into_bytes()] {
+        TestCase {
+            program,
+            input: input.clone(),
+            desired_out: desired_out.clone(),
+        }
+        .test()
+    }
+}
+
+#[test]
+fn optimized_easy_opt() {
+    // Takes billions of instructions without `MulAdd`, so only the optimized program is run
+    let case = TestCase {
+        program: include_bytes!("../bf_programs/EasyOpt.b").to_vec(),
+        input: vec![],
+        desired_out: b"OK\n".to_vec(),
+    };
+    let program = peephole::default_peephole_opt(BfIrScope::parse_sl(&case.program).unwrap());
+    case.test_backend(&mut InterpreterBackend::default(), program.clone());
+    case.test_backend(&mut JitBackend::default(), program);
+}
+
+#[test]
+fn interpreter_run_outcome() {
+    let program = BfIrScope::parse_sl(b"++[-]").unwrap();
+    let outcome = Interpreter::new(program, io_utils::void())
+        .run_drop()
+        .unwrap();
+    assert_eq!(outcome.instructions, 6);
+
+    let program = BfIrScope::parse_sl(b".,.").unwrap();
+    let mut stdout = Vec::new();
+    let res = Interpreter::new(
+        program,
+        io_utils::io_triple(ReadIter::empty(), &mut stdout, empty()).with_eoi::<EOIPanic>(),
+    )
+    .run_drop();
+
+    let Err(RunError::Read(e)) = res else {
+        panic!("Expected a read error, got {res:?}")
+    };
+    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+    assert_eq!(stdout, b"\0");
+}
+
+#[test]
+fn interpreter_debugger() {
+    // Ops: `++`, `[`, `>+++<-`, `]`, `>`, `.`
+    let program = BfIrScope::parse_sl(b"++[>+++<-]>.").unwrap();
+    let mut stdout = Vec::new();
+    let mut interp = Interpreter::new(
+        program,
+        io_utils::io_triple(ReadIter::empty(), &mut stdout, empty()),
+    );
+
+    assert_eq!(interp.step().unwrap(), StopReason::Paused);
+    assert_eq!(interp.location(), Some(InsLocation(1)));
+    assert_eq!(interp.tape()[0].0, 2);
+
+    let loop_end = interp.location_of_path(&[1, 1]).unwrap();
+    assert_eq!(loop_end, InsLocation(3));
+    assert_eq!(interp.token_path(InsLocation(2)), [1, 0]);
+    interp.add_breakpoint(loop_end);
+    assert_eq!(interp.resume().unwrap(), StopReason::Breakpoint(loop_end));
+    assert_eq!(interp.tape()[1].0, 3);
+    // Resuming from a breakpoint continues past it
+    assert_eq!(interp.resume().unwrap(), StopReason::Breakpoint(loop_end));
+    assert_eq!(interp.tape()[1].0, 6);
+    interp.remove_breakpoint(loop_end);
+
+    // Rewinds to the loop body, which stops once cell 0 is cleared
+    interp.tape_mut()[0].0 = 2;
+    interp.set_location(InsLocation(2));
+    interp.add_watchpoint(0, Some(0));
+    assert_eq!(
+        interp.resume().unwrap(),
+        StopReason::Watchpoint {
+            cell: 0,
+            old: 1,
+            new: 0
+        }
+    );
+    assert_eq!(interp.tape()[1].0, 12);
+
+    assert_eq!(interp.step_until_io().unwrap(), StopReason::Paused);
+    assert_eq!(interp.data_ptr(), 1);
+    assert!(interp.is_finished());
+    assert_eq!(interp.step_n(10).unwrap(), StopReason::Finished);
+
+    interp.tape_mut()[2].0 = b'A';
+    interp.set_data_ptr(2);
+    interp.set_location(InsLocation(5));
+    assert_eq!(interp.step().unwrap(), StopReason::Finished);
+    drop(interp);
+    assert_eq!(stdout, [12, b'A']);
+
+    // A breakpoint on the first instruction stops before anything is executed
+    let program = BfIrScope::parse_sl(b"+>+").unwrap();
+    let mut interp = Interpreter::new(program, io_utils::void());
+    interp.add_breakpoint(InsLocation(0));
+    assert_eq!(
+        interp.resume().unwrap(),
+        StopReason::Breakpoint(InsLocation(0))
+    );
+    assert_eq!(interp.tape()[0].0, 0);
+    assert_eq!(interp.resume().unwrap(), StopReason::Finished);
+    // So does one which execution was moved to
+    interp.set_location(InsLocation(0));
+    assert_eq!(
+        interp.step_n(5).unwrap(),
+        StopReason::Breakpoint(InsLocation(0))
+    );
+    assert_eq!(interp.step_n(5).unwrap(), StopReason::Finished);
+    assert_eq!(interp.tape()[..2], [Wrapping(2), Wrapping(2)]);
+}
+
+#[test]
+fn fuel_limits() {
+    // Writes once, then loops forever
+    let program = BfIrScope::parse_sl(b"+.[>+<]").unwrap();
+    fn check_out_of_fuel(backend: &mut impl Backend, program: BfIrScope) {
+        let mut stdout = Vec::new();
+        let res = backend
+            .run(
+                program,
+                io_utils::io_triple(ReadIter::empty(), &mut stdout, empty()),
+            )
+            .unwrap();
+        assert!(
+            res.out_of_fuel,
+            "`{}` did not run out of {}",
+            backend.name(),
+            backend.fuel_unit()
+        );
+        assert!(res.io_error.is_none());
+        assert_eq!(stdout, [1]);
+        assert_eq!(res.tape[0], 1);
+    }
+    check_out_of_fuel(
+        &mut InterpreterBackend {
+            fuel_limit: Some(100),
+            ..Default::default()
+        },
+        program.clone(),
+    );
+    check_out_of_fuel(
+        &mut JitBackend {
+            fuel_limit: Some(10),
+            ..Default::default()
+        },
+        program.clone(),
+    );
+
+    // The JIT spends one unit of fuel per iteration
+    let mut state = JitState {
+        fuel: Some(10),
+        ..JitState::with_len(8)
+    };
+    let exit = JitProgram::with_config(program, &CodegenConfig::default())
+        .unwrap()
+        .run_with_state(&mut io_utils::void(), &mut state)
+        .unwrap();
+    assert_eq!(exit, JitExit::OutOfFuel);
+    assert_eq!(state.tape[..2], [1, 10]);
+    assert_eq!(state.fuel, Some(0));
+
+    // The interpreter can continue once refueled
+    let program = BfIrScope::parse_sl(b"+++[-]").unwrap();
+    let mut interp = Interpreter::new(program, io_utils::void());
+    interp.set_fuel(Some(5));
+    assert!(matches!(interp.run(), Err(RunError::OutOfFuel)));
+    interp.set_fuel(Some(3));
+    assert_eq!(interp.run().unwrap().instructions, 3);
+    assert_eq!(interp.fuel(), Some(0));
+    assert!(interp.is_finished());
+
+    // A `Scan` spends fuel for every step, and stops if it can never reach a zero cell
+    let mut interp = Interpreter::new(BfIrScope::from(vec![BfIrTok::Scan(2)]), io_utils::void());
+    interp.tape_mut().fill(Wrapping(1));
+    // Odd cells are out of reach
+    interp.tape_mut()[3].0 = 0;
+    interp.set_fuel(Some(1000));
+    assert!(matches!(interp.run(), Err(RunError::OutOfFuel)));
+    assert_eq!((interp.fuel(), interp.data_ptr()), (Some(0), 0));
+    interp.set_fuel(None);
+    assert!(matches!(interp.run(), Err(RunError::NeverHalts)));
+    assert_eq!((interp.fuel(), interp.data_ptr()), (None, 0));
+    // The scan continues from where the fuel ran out
+    interp.tape_mut()[10].0 = 0;
+    interp.set_fuel(Some(3));
+    assert!(matches!(interp.run(), Err(RunError::OutOfFuel)));
+    assert_eq!((interp.fuel(), interp.data_ptr()), (Some(0), 4));
+    interp.set_fuel(Some(10));
+    interp.run().unwrap();
+    assert_eq!((interp.fuel(), interp.data_ptr()), (Some(6), 10));
+
+    // A stride of `0` never leaves a nonzero cell, like `[]`
+    let inc = BfIrScope::parse_sl("+").unwrap()[0].clone();
+    let stuck = BfIrScope::from(vec![inc, BfIrTok::Scan(0)]);
+    // Loops spend fuel even when it's unlimited, which must not be mistaken for running out
+    let mut looped = BfIrScope::parse_sl("+[.-]+").unwrap().to_vec();
+    looped.push(BfIrTok::Scan(0));
+    let looped = BfIrScope::from(looped);
+    for program in [stuck, looped] {
+        for (fuel_limit, out_of_fuel, never_halts) in [(Some(10), true, false), (None, false, true)]
+        {
+            let interp = InterpreterBackend {
+                fuel_limit,
+                ..Default::default()
+            }
+            .run(program.clone(), io_utils::void())
+            .unwrap();
+            let jit = JitBackend {
+                fuel_limit,
+                ..Default::default()
+            }
+            .run(program.clone(), io_utils::void())
+            .unwrap();
+            for res in [interp, jit] {
+                assert_eq!(
+                    (res.out_of_fuel, res.never_halts),
+                    (out_of_fuel, never_halts)
+                );
+                assert_eq!((res.tape[0], res.data_ptr), (1, 0));
+            }
+        }
+    }
+    let in_place = BfIrScope::from(vec![BfIrTok::Scan(0)]);
+    let mut interp = Interpreter::new(in_place.clone(), io_utils::void());
+    assert_eq!(interp.run().unwrap().instructions, 1);
+    let exit = JitProgram::with_config(in_place, &CodegenConfig::default())
+        .unwrap()
+        .run_with_state(&mut io_utils::void(), &mut JitState::with_len(8))
+        .unwrap();
+    assert_eq!(exit, JitExit::Finished);
+
+    // Tapes shorter than a 16 cell window are checked one cell at a time
+    for len in [8, 64] {
+        for stride in [1, -1, 3] {
+            let mut state = JitState {
+                tape: vec![1; len].into_boxed_slice(),
+                fuel: Some(1000),
+                ..Default::default()
+            };
+            let exit = JitProgram::with_config(
+                BfIrScope::from(vec![BfIrTok::Scan(stride)]),
+                &CodegenConfig::default(),
+            )
+            .unwrap()
+            .run_with_state(&mut io_utils::void(), &mut state)
+            .unwrap();
+            assert_eq!(exit, JitExit::OutOfFuel, "stride {stride} on {len} cells");
+            assert_eq!(state.fuel, Some(0));
+            assert!(state.data_ptr < len);
+        }
+    }
+    let mut state = JitState {
+        data_ptr: 2,
+        fuel: Some(100),
+        ..JitState::with_len(8)
+    };
+    state.tape.fill(1);
+    state.tape[5] = 0;
+    let exit = JitProgram::with_config(
+        BfIrScope::from(vec![BfIrTok::Scan(1)]),
+        &CodegenConfig::default(),
+    )
+    .unwrap()
+    .run_with_state(&mut io_utils::void(), &mut state)
+    .unwrap();
+    assert_eq!(
+        (exit, state.fuel, state.data_ptr),
+        (JitExit::Finished, Some(97), 5)
+    );
+
+    // A zero found within a 16 cell window costs its distance, not the whole window
+    for (stride, start, zero) in [(1, 20, 30), (-1, 40, 30)] {
+        let scan = |fuel| {
+            let mut state = JitState {
+                tape: vec![1; 64].into_boxed_slice(),
+                data_ptr: start,
+                fuel: Some(fuel),
+            };
+            state.tape[zero] = 0;
+            let exit = JitProgram::with_config(
+                BfIrScope::from(vec![BfIrTok::Scan(stride)]),
+                &CodegenConfig::default(),
+            )
+            .unwrap()
+            .run_with_state(&mut io_utils::void(), &mut state)
+            .unwrap();
+            (exit, state.fuel, state.data_ptr)
+        };
+        let stopped = start.wrapping_add_signed(5 * stride);
+        assert_eq!(scan(5), (JitExit::OutOfFuel, Some(0), stopped));
+        assert_eq!(scan(12), (JitExit::Finished, Some(2), zero));
+    }
+}
+
+#[test]
+fn loop_profile() {
+    // Ops: `++`, `[`, `>+++`, `[`, `>+<-`, `]`, `<-`, `]`
+    let program = BfIrScope::parse_sl(b"++[>+++[>+<-]<-]").unwrap();
+    let mut interp = Interpreter::new(program, io_utils::void());
+    assert!(interp.profile().is_none());
+    interp.enable_profiling();
+    let outcome = interp.run().unwrap();
+
+    let profile = interp.profile().unwrap();
+    assert_eq!(profile.instructions, outcome.instructions);
+    assert_eq!(profile.instructions, 22);
+    let [outer, inner] = &profile.loops[..] else {
+        panic!("Expected two loops, got {profile:?}")
+    };
+    assert_eq!(
+        (
+            &outer.path[..],
+            outer.depth,
+            outer.iterations,
+            outer.instructions
+        ),
+        (&[1][..], 1, 2, 20)
+    );
+    assert_eq!(
+        (
+            &inner.path[..],
+            inner.depth,
+            inner.iterations,
+            inner.instructions
+        ),
+        (&[1, 1][..], 2, 6, 12)
+    );
+    // Without a `Scan`, every instruction costs one unit
+    assert_eq!((profile.cost, inner.cost), (22, 12));
+    assert_eq!(inner.share, 12. / 22.);
+    assert_eq!(profile.hottest(1), &profile.loops[..1]);
+
+    let json: serde_json::Value = serde_json::from_str(&profile.to_json()).unwrap();
+    assert_eq!(json["loops"][1]["iterations"], 6);
+    assert!(profile.to_string().contains("54.55%"));
+
+    // A `Scan` also costs one unit for each step it takes, here over 5 cells
+    let scan_loop = BfIrScope::from(vec![BfIrTok::Scan(1)]);
+    let mut interp = Interpreter::new(
+        BfIrScope::from(vec![BfIrTok::Loop(scan_loop)]),
+        io_utils::void(),
+    );
+    interp.tape_mut()[..5].fill(Wrapping(1));
+    interp.enable_profiling();
+    interp.run().unwrap();
+    let profile = interp.profile().unwrap();
+    assert_eq!((profile.instructions, profile.cost), (3, 8));
+    let scan_loop = &profile.loops[0];
+    assert_eq!((scan_loop.instructions, scan_loop.cost), (2, 7));
+    assert_eq!(scan_loop.share, 7. / 8.);
+}
+
+#[test]
+fn source_spans() {
+    let src = "a +>+\n[-\u{e9}.]\n,";
+    let span = |start, end, line, col| Span {
+        start,
+        end,
+        line,
+        col,
+    };
+
+    let bf = BfParser::new(src.as_bytes()).parse().unwrap();
+    assert_eq!(
+        bf.spans(),
+        [
+            span(2, 3, 1, 3),
+            span(3, 4, 1, 4),
+            span(4, 5, 1, 5),
+            span(6, 12, 2, 1),
+            span(13, 14, 3, 1),
+        ]
+    );
+    let BfTok::Loop(inner) = &bf[3] else {
+        panic!("Expected a loop, got {:?}", bf[3])
+    };
+    // The non-ASCII comment is two bytes wide
+    assert_eq!(inner.spans(), [span(7, 8, 2, 2), span(10, 11, 2, 5)]);
+
+    // Merged tokens cover all of their sources
+    let program = BfIrScope::from_bf(bf);
+    assert_eq!(
+        program.spans(),
+        [span(2, 5, 1, 3), span(6, 12, 2, 1), span(13, 14, 3, 1)]
+    );
+
+    let interp = Interpreter::new(program, io_utils::void());
+    assert_eq!(interp.span(InsLocation(0)), span(2, 5, 1, 3));
+    // Both ends of the loop map to the whole loop
+    let loop_end = interp.location_of_path(&[1, 2]).unwrap();
+    assert_eq!(interp.span(loop_end), span(6, 12, 2, 1));
+    assert_eq!(interp.span(loop_end).to_string(), "2:1");
+}
+
+#[test]
+fn source_breakpoints() {
+    // Ops: `++`, `[`, `>+++<-`, `]`, `.`
+    let src = b"++\n[>+++<-] .";
+    let program = BfIrScope::parse_sl(src).unwrap();
+    let mut interp = Interpreter::new(program, io_utils::void());
+
+    // Both `+`s are a single instruction
+    assert_eq!(interp.locations_at(src, 1, 2), [InsLocation(0)]);
+    assert_eq!(interp.locations_at(src, 1, 3), []);
+    // The loop body, rather than the loop around it
+    assert_eq!(interp.locations_at(src, 2, 4), [InsLocation(2)]);
+    assert_eq!(
+        interp.locations_at(src, 2, 8),
+        [InsLocation(1), InsLocation(3)]
+    );
+    assert_eq!(interp.locations_at(src, 2, 9), []);
+    assert_eq!(interp.locations_at(src, 2, 10), [InsLocation(4)]);
+
+    for loc in interp.locations_at(src, 2, 3) {
+        interp.add_breakpoint(loc);
+    }
+    assert_eq!(
+        interp.resume().unwrap(),
+        StopReason::Breakpoint(InsLocation(2))
+    );
+    assert_eq!(interp.tape()[1].0, 0);
+    assert_eq!(
+        interp.resume().unwrap(),
+        StopReason::Breakpoint(InsLocation(2))
+    );
+    assert_eq!(interp.tape()[1].0, 3);
+
+    // A loop which spans several lines is found from its `]` as well as its `[`
+    let src = b"+[\n->+<\n]";
+    let interp = Interpreter::new(BfIrScope::parse_sl(src).unwrap(), io_utils::void());
+    let checks = [InsLocation(1), InsLocation(3)];
+    assert_eq!(interp.locations_at(src, 1, 2), checks);
+    assert_eq!(interp.locations_at(src, 3, 1), checks);
+    assert_eq!(interp.locations_at_offset(8), checks);
+    assert_eq!(interp.locations_at(src, 2, 3), [InsLocation(2)]);
+    // Past the end of a line or the source
+    assert_eq!(interp.locations_at(src, 1, 3), []);
+    assert_eq!(interp.locations_at(src, 4, 1), []);
+}
+
+#[test]
+fn unbalanced_brackets() {
+    let parse = |src: &str| BfParser::new(src.as_bytes()).parse();
+
+    let Err(ParseError::UnmatchedClose { close }) = parse("+[-]\n-]+") else {
+        panic!("Expected an unmatched `]`")
+    };
+    assert_eq!((close.line, close.col), (2, 2));
+
+    let res = parse("[[\n]");
+    let Err(ParseError::UnclosedLoop { open, end }) = &res else {
+        panic!("Expected an unclosed `[`, got {res:?}")
+    };
+    assert_eq!((open.line, open.col), (1, 1));
+    assert_eq!((end.line, end.col), (2, 2));
+    assert_eq!((end.start, end.end), (4, 4));
+    // The program ends before the `!` of its input
+    let res = BfParser::new("[+!]".as_bytes()).parse_with_input();
+    let Err(ParseError::UnclosedLoop { end, .. }) = res else {
+        panic!("Expected an unclosed `[`")
+    };
+    assert_eq!((end.start, end.end, end.col), (2, 2, 3));
+    assert_eq!(
+        res.unwrap_err().to_string(),
+        "unclosed `[` at 1:1, expected `]` before the end at 2:2"
+    );
+
+    // Lenient parsing stops at a stray `]`, and closes loops at the end
+    let bf = BfParser::new("+]+".as_bytes()).lenient().parse().unwrap();
+    assert_eq!(bf.len(), 1);
+    let bf = BfParser::new("+[-".as_bytes()).lenient().parse().unwrap();
+    assert!(matches!(&bf[1], BfTok::Loop(inner) if inner.len() == 1));
+}
+
+#[test]
+fn non_utf8_source() {
+    // Latin-1 and binary comments
+    let src = b"caf\xe9 +\xff\x80[-]\xe9\n.";
+    let bf = BfParser::new(&src[..]).parse().unwrap();
+    assert_eq!(bf.len(), 3);
+    assert_eq!((bf.spans()[1].start, bf.spans()[1].end), (8, 11));
+    assert_eq!((bf.spans()[2].line, bf.spans()[2].col), (2, 1));
+
+    let res = BfParser::new(&src[..]).utf8().parse();
+    assert!(matches!(res, Err(ParseError::Utf8(_))), "{res:?}");
+    // Both paths agree on valid UTF-8
+    let src = "caf\u{e9} +[-]\n.".as_bytes();
+    let bytes = BfParser::new(src).parse().unwrap();
+    let chars = BfParser::new(src).utf8().parse().unwrap();
+    assert_eq!(bytes.spans(), chars.spans());
+}
+
+#[test]
+fn deep_nesting() {
+    const DEPTH: usize = 100_000;
+    let src = ["+", &"[".repeat(DEPTH), "-", &"]".repeat(DEPTH), "."].concat();
+
+    let bf = BfParser::new(src.as_bytes()).parse().unwrap();
+    assert_eq!(format!("{bf:?}").matches("Loop([").count(), DEPTH);
+    let program = BfIrScope::from_bf(bf);
+    assert_eq!(program.len(), DEPTH + 3);
+    assert_eq!(program.largest_subscope().len_flat(), 3);
+    assert_eq!(program.subscopes().count(), DEPTH + 1);
+
+    /// Visits every token without changing anything
+    struct Visit(usize);
+    impl PeepholePass for Visit {
+        fn apply(&mut self, _: &[BfIrTok]) -> PeepholeApply {
+            self.0 += 1;
+            PeepholeApply::Pass
+        }
+    }
+    let mut visit = Visit(0);
+    let program = peephole::apply_pass(program, &mut visit);
+    assert_eq!(visit.0, DEPTH + 3);
+
+    let mut stdout = Vec::new();
+    Interpreter::new(
+        program.clone(),
+        io_utils::io_triple(ReadIter::empty(), &mut stdout, empty()),
+    )
+    .run_drop()
+    .unwrap();
+    assert_eq!(stdout, [0]);
+
+    // `Debug` doesn't recurse either
+    let debug = format!("{program:?}");
+    assert!(debug.starts_with("[Modify { adds: Adds([(0, 1)]), ptr_delta: 0 }, Loop([Loop(["));
+    assert!(debug.ends_with("])]), Write]"));
+    assert_eq!(debug.matches("Loop([").count(), DEPTH);
+
+    // Only builds the IR, since Cranelift's own passes take quadratic time on deep nesting
+    let config = CodegenConfig {
+        enable_verifier: false,
+        ..Default::default()
+    };
+    let mut module = JITModule::new(JITBuilder::with_isa(
+        config.isa().unwrap(),
+        default_libcall_names(),
+    ));
+    let func = compile_cranelift::compile(program, &mut module).unwrap();
+    assert!(func.dfg.num_blocks() > DEPTH);
+
+    // Loop bodies are indented under their loop
+    let program = BfIrScope::parse_sl("+[.[,]]").unwrap();
+    assert_eq!(program.to_string(), "+1@0, >0\n  .\n      ,");
+    assert_eq!(
+        format!("{:?}", &program[1..]),
+        format!("{:?}", BfIrScope::parse_sl("[.[,]]").unwrap())
+    );
+    assert_eq!(
+        format!("{:#?}", BfIrScope::parse_sl("[.[]],").unwrap()),
+        "[\n    Loop([\n        Write,\n        Loop([]),\n    ]),\n    Read,\n]"
+    );
+}
+
+#[test]
+fn peephole_passes() {
+    let opt = |src: &str| peephole::default_peephole_opt(BfIrScope::parse_sl(src).unwrap());
+
+    // Clear loops with any odd step become a `Set`, but even steps may never finish
+    for src in ["[-]", "[+]", "[---]", "[+++++]"] {
+        assert!(matches!(opt(src)[..], [BfIrTok::Set(0)]), "{src}");
+    }
+    assert!(matches!(opt("[--]")[..], [BfIrTok::Loop(_)]));
+    assert!(matches!(opt("[->]")[..], [BfIrTok::Loop(_)]));
+
+    // Adds around a `Set` are folded into it
+    assert_eq!(opt("+++[-]++").to_string(), "=2");
+    assert_eq!(opt("+>+<[-]").to_string(), "+1@1, >0\n=0");
+    assert_eq!(opt("[-]+>+").to_string(), "=1\n+1@1, >1");
+    assert_eq!(opt("[-]++[-]").to_string(), "=0");
+    // Pointer moves around a `Set` are kept
+    assert_eq!(opt(">[-]<").to_string(), ", >1\n=0\n, >-1");
+    // `Modify`s with no effect are removed
+    assert_eq!(opt("+-<>.").to_string(), ".");
+
+    // The second `Modify` is shifted by the pointer move of the first
+    let modify = |src: &str| BfIrScope::parse_sl(src).unwrap()[0].clone();
+    let program = BfIrScope::from(vec![modify("+>"), modify("-->+<")]);
+    let program = peephole::apply_pass(program, &mut DataAddFold);
+    assert_eq!(program.to_string(), "+1@0 -2@1 +1@2, >1");
+    let program = peephole::apply_pass(
+        BfIrScope::from(vec![modify("+>"), modify("<-")]),
+        &mut DataAddFold,
+    );
+    let program = peephole::apply_pass(program, &mut PtrAddFold);
+    assert!(program.is_empty());
+
+    let program = opt("+[>[-]<-]");
+    assert!(matches!(&program[1], BfIrTok::Loop(inner) if matches!(inner[1], BfIrTok::Set(0))));
+
+    // Balanced loops with an odd step become a `MulAdd`, with factors scaled by the number of iterations
+    assert_eq!(opt("[->+>++<<]").to_string(), "*[+1@1 +2@2]");
+    assert_eq!(opt("[+<+>]").to_string(), "*[-1@-1]");
+    assert_eq!(opt("[--->+<]").to_string(), "*[-85@1]");
+    assert!(matches!(opt("[-->+<]")[..], [BfIrTok::Loop(_)]));
+    assert!(matches!(opt("[->+<<]")[..], [BfIrTok::Loop(_)]));
+
+    // Runs `,[--->+>++<<]>.>.` with 7: the loop runs 7 / 3 = 173 (mod 256) times
+    TestCase {
+        program: b",[--->+>++<<]>.>.".to_vec(),
+        input: vec![7],
+        desired_out: vec![173, 90],
+    }
+    .test();
+    // Every starting value matches the unoptimized loop
+    let src = "[--->+>-----<<<+++>]";
+    for val in 0..=u8::MAX {
+        let tapes = [BfIrScope::parse_sl(src).unwrap(), opt(src)].map(|program| {
+            let mut interp = Interpreter::new(program, io_utils::void());
+            interp.set_data_ptr(1);
+            interp.tape_mut()[1] = Wrapping(val);
+            interp.run().unwrap();
+            interp.tape()[..4].to_vec()
+        });
+        assert_eq!(tapes[0], tapes[1], "starting from {val}");
+    }
+}
+
+#[test]
+fn pass_manager() {
+    let program = || BfIrScope::parse_sl("+>+-<[-]>[-<+>]<[>]").unwrap();
+
+    // The default pipeline is empty
+    let (unchanged, report) = PassManager::default().run(program());
+    assert_eq!(unchanged.to_string(), program().to_string());
+    assert!(report.passes.is_empty() && report.converged);
+
+    // A single pass runs once, even if it made changes
+    let (_, report) = PassManager::new().with_pass(LoopSet0).run(program());
+    assert_eq!(report.iterations, 1);
+    assert!(!report.converged);
+    assert_eq!(report.rewrites_by("LoopSet0"), 1);
+    assert_eq!(report.passes[0].token_delta, -1);
+
+    let (optimized, report) = OptLevel::O3.pass_manager().run(program());
+    assert_eq!(
+        optimized.to_string(),
+        peephole::default_peephole_opt(program()).to_string()
+    );
+    assert!(report.converged);
+    assert_eq!(report.passes.len(), 7 * report.iterations);
+    // The last iteration found nothing to rewrite
+    let last = &report.passes[report.passes.len() - 7..];
+    assert!(last.iter().all(|p| p.rewrites == 0 && p.token_delta == 0));
+    assert_eq!(report.rewrites_by("LoopSet0"), 1);
+    assert_eq!(report.rewrites_by("MulAddLoop"), 1);
+    assert_eq!(report.rewrites_by("LoopScan"), 1);
+    assert_eq!(
+        report.passes.iter().map(|p| p.token_delta).sum::<isize>(),
+        report.tokens_after as isize - report.tokens_before as isize
+    );
+
+    let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(json["passes"][0]["name"], "DataAddFold");
+    assert_eq!(json["tokens_after"], optimized.len());
+    assert!(report.to_string().contains("MulAddLoop"));
+}
+
+#[test]
+fn opt_levels() {
+    let src = b"+[-][+-]>[-<+>]<[>]++.";
+    let program = || BfIrScope::from_bf(BfParser::new(&src[..]).parse().unwrap());
+
+    // O0 doesn't touch the program at all
+    assert_eq!(
+        OptLevel::O0.optimize(program()).to_string(),
+        program().to_string()
+    );
+    let (_, report) = OptLevel::O0.pass_manager().run(program());
+    assert!(report.passes.is_empty());
+    assert_eq!(report.tokens_before, report.tokens_after);
+
+    let len = |level: OptLevel| level.optimize(program()).len();
+    assert!(len(OptLevel::O1) < len(OptLevel::O0));
+    assert!(len(OptLevel::O2) < len(OptLevel::O1));
+    assert!(len(OptLevel::O3) <= len(OptLevel::O2));
+    assert!(!OptLevel::O1
+        .optimize(program())
+        .iter()
+        .any(|tok| matches!(tok, BfIrTok::Set(_))));
+
+    for level in OptLevel::ALL {
+        assert_eq!(level.to_string().parse(), Ok(level));
+        let case = TestCase {
+            program: src.to_vec(),
+            input: vec![],
+            desired_out: vec![2],
+        };
+        let interp = case.test_backend(
+            &mut InterpreterBackend {
+                opt_level: level,
+                ..Default::default()
+            },
+            program(),
+        );
+        let jit = case.test_backend(
+            &mut JitBackend {
+                config: CodegenConfig::default().with_ir_opt_level(level),
+                ..Default::default()
+            },
+            program(),
+        );
+        assert_eq!((interp.data_ptr, interp.tape), (jit.data_ptr, jit.tape));
+    }
+    assert!("4".parse::<OptLevel>().is_err());
+}
+
+#[test]
+fn dead_code_elim() {
+    let run = |src: &str| {
+        let program = OptLevel::O2.optimize(BfIrScope::parse_sl(src).unwrap());
+        let (program, removed) = DeadCodeElim.run(program);
+        (program.to_string(), removed)
+    };
+    let expected = |src: &str| {
+        OptLevel::O2
+            .optimize(BfIrScope::parse_sl(src).unwrap())
+            .to_string()
+    };
+
+    // Every idiom and loop is dead once the cell is left zero
+    assert_eq!(run("[-][>][-<+>][-][.]."), (expected("[-]."), 4));
+    // Changing other cells keeps the current one zero, but moving away doesn't
+    assert_eq!(run("[>+<]>+<[.]."), (expected("[>+<]>+<."), 1));
+    assert_eq!(run("[-]>[.]").1, 0);
+    // Nothing is known about the starting tape, nor at the start of a loop body
+    assert_eq!(run("[-]").1, 0);
+    assert_eq!(run("[.[-]]").1, 0);
+    assert_eq!(run("[.[-][.]]").1, 1);
+
+    // O3 includes it, and reports its rewrites
+    let (program, report) = OptLevel::O3
+        .pass_manager()
+        .run(BfIrScope::parse_sl(",[.,][>++<-][.]").unwrap());
+    assert_eq!(program.to_string(), expected(",[.,]"));
+    assert_eq!(report.rewrites_by("DeadCodeElim"), 2);
+}
+
+#[test]
+fn modify_adds_order() {
+    // Adds are sorted by offset, and changes which cancel out are dropped
+    let program = BfIrScope::parse_sl("+>--<<+++>>>+-").unwrap();
+    assert_eq!(program.to_string(), "+3@-1 +1@0 -2@1, >2");
+    let BfIrTok::Modify { adds, .. } = &program[0] else {
+        panic!("Expected a Modify, got {:?}", program[0])
+    };
+    assert_eq!(adds.get(1), Wrapping(-2));
+    assert_eq!(adds.get(2), Wrapping(0));
+
+    // Equal changes are equal regardless of the order they were made in
+    let forward = [(0, Wrapping(1)), (-3, Wrapping(2)), (5, Wrapping(-1))];
+    let a: Adds = forward.into_iter().collect();
+    let b: Adds = forward.into_iter().rev().collect();
+    assert_eq!(a, b);
+    let hash = |adds: &Adds| {
+        let mut h = std::hash::DefaultHasher::new();
+        adds.hash(&mut h);
+        h.finish()
+    };
+    assert_eq!(hash(&a), hash(&b));
+}
+
+#[test]
+fn inline_input() {
+    TestCase::from_inline(
+        include_bytes!("../bf_programs/echo_inline.b"),
+        b"inline input\n",
+    )
+    .test();
+
+    // Only the first `!` splits, and the input is kept as raw bytes
+    let src = b"+[,.]! a!b\xff[";
+    let (program, input) = BfParser::new(&src[..]).parse_with_input().unwrap();
+    assert_eq!(program.len(), 2);
+    assert_eq!(input, b" a!b\xff[");
+
+    // Without a `!`, there is no input
+    let (program, input) = BfParser::new("+[-]".as_bytes()).parse_with_input().unwrap();
+    assert_eq!((program.len(), input.len()), (2, 0));
+
+    // An unmatched `]` ends a lenient program early, but the input still starts after the `!`
+    let (program, input) = BfParser::new("+]-!in".as_bytes())
+        .lenient()
+        .parse_with_input()
+        .unwrap();
+    assert_eq!((program.len(), &input[..]), (1, &b"in"[..]));
+
+    // Loops must be closed before the input
+    let res = BfParser::new("+[!]".as_bytes()).parse_with_input();
+    let Err(ParseError::UnclosedLoop { end, .. }) = res else {
+        panic!("Expected an unclosed loop, got {res:?}")
+    };
+    assert_eq!(end.start, 2);
+}
+
+#[test]
+fn debug_commands() {
+    let src = b"+++>++#<.#";
+    // `#` is a comment unless enabled
+    let plain = BfIrScope::parse_sl(src).unwrap();
+    assert_eq!(plain.len(), 2);
+
+    let bf = BfParser::new(&src[..]).debug_commands().parse().unwrap();
+    let program = BfIrScope::from_bf(bf);
+    assert_eq!(program.len(), 5);
+    assert!(matches!(program[1], BfIrTok::Debug));
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    Interpreter::new(
+        program.clone(),
+        io_utils::io_triple(ReadIter::empty(), &mut stdout, &mut stderr),
+    )
+    .run_drop()
+    .unwrap();
+    assert_eq!(stdout, [3]);
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "# at 1:7 (instruction 1), data_ptr = 1, cells 0..10: 3 [2] 0 0 0 0 0 0 0 0\n\
+         # at 1:10 (instruction 4), data_ptr = 0, cells 0..9: [3] 2 0 0 0 0 0 0 0\n"
+    );
+
+    // The debugger reports dumps instead of writing them
+    let mut stderr = Vec::new();
+    let mut interp = Interpreter::new(
+        program.clone(),
+        io_utils::io_triple(ReadIter::empty(), std::io::sink(), &mut stderr),
+    );
+    let Ok(StopReason::Debug(dump)) = interp.resume() else {
+        panic!("Expected to stop at the first `#`")
+    };
+    assert_eq!(dump.location, InsLocation(1));
+    assert_eq!((dump.data_ptr, dump.start), (1, 0));
+    assert_eq!(dump.cells[..3], [3, 2, 0]);
+    assert!(matches!(interp.resume(), Ok(StopReason::Debug(_))));
+    assert_eq!(interp.resume().unwrap(), StopReason::Finished);
+    drop(interp);
+    assert!(stderr.is_empty());
+
+    // The JIT ignores it
+    TestCase {
+        program: src.to_vec(),
+        input: vec![],
+        desired_out: vec![3],
+    }
+    .test_backend(&mut JitBackend::default(), program);
+}
+
+#[test]
+fn bf_formatting() {
+    let src = b"comment +++[>++<-] >[-]<<.\n[,.]";
+    let bf = BfParser::new(&src[..]).parse().unwrap();
+    let minified = BfSource::from_bf(&bf, FmtStyle::Minified).to_string();
+    assert_eq!(minified, "+++[>++<-]>[-]<<.[,.]");
+
+    let style = FmtStyle::Pretty {
+        indent: 2,
+        width: 6,
+    };
+    let bf = BfParser::new("+++++++[->[-]++<],".as_bytes())
+        .parse()
+        .unwrap();
+    assert_eq!(
+        BfSource::from_bf(&bf, style).to_string(),
+        "++++++\n+\n[\n  ->\n  [-]\n  ++<\n]\n,"
+    );
+
+    // Pretty printing keeps comments on their own lines, and minifying drops them
+    let src = b"add two +>++<\n[ move it\n  ->+<]\n  done";
+    let bf = BfParser::new(&src[..]).parse().unwrap();
+    let style = FmtStyle::Pretty {
+        indent: 2,
+        width: 80,
+    };
+    let pretty = BfSource::with_comments(&bf, src, style).to_string();
+    assert_eq!(pretty, "add two\n+>++<\n[\n  move it\n  ->+<\n]\ndone");
+    let reparsed = BfParser::new(pretty.as_bytes()).parse().unwrap();
+    assert_eq!(
+        BfSource::with_comments(&reparsed, pretty.as_bytes(), style).to_string(),
+        pretty
+    );
+    assert_eq!(
+        BfSource::with_comments(&bf, src, FmtStyle::Minified).to_string(),
+        "+>++<[->+<]"
+    );
+
+    // Comments in any encoding are written back byte for byte
+    let src = b"caf\xe9 +\n[\xff\x80 -]";
+    let write = |src: &[u8]| {
+        let bf = BfParser::new(src).parse().unwrap();
+        let mut out = vec![];
+        BfSource::with_comments(&bf, src, style)
+            .write_to(&mut out)
+            .unwrap();
+        out
+    };
+    let pretty = write(src);
+    assert_eq!(pretty, b"caf\xe9\n+\n[\n  \xff\x80\n  -\n]");
+    assert_eq!(write(&pretty), pretty);
+
+    // Lowering BFIR keeps the behavior of the program
+    let program = include_bytes!("../bf_programs/awib-0.4.bf");
+    let input = include_bytes!("../bf_programs/test_1.bf");
+    let run = |program: &[u8]| {
+        let mut stdout = Vec::new();
+        Interpreter::new(
+            BfIrScope::parse_sl(program).unwrap(),
+            io_utils::io_triple(&input[..], &mut stdout, empty()),
+        )
+        .run_drop()
+        .unwrap();
+        stdout
+    };
+    let desired_out = run(program);
+    let ir = BfIrScope::parse_sl(program).unwrap();
+    for style in [FmtStyle::Minified, FmtStyle::default()] {
+        let lowered = BfSource::from_ir(&ir, style).to_string();
+        assert_eq!(run(lowered.as_bytes()), desired_out);
+    }
+
+    // Offsets are visited in the direction which ends closest to the final pointer
+    let ir = BfIrScope::parse_sl(">+>>-<<<<++").unwrap();
+    let lowered = BfSource::from_ir(&ir, FmtStyle::Minified);
+    assert_eq!(lowered.commands(), b">>>-<<+<<++");
+}
+
+#[test]
+fn jit_eoi_error() {
+    let program = BfIrScope::parse_sl(b".,.").unwrap();
+
+    let mut stdout = Vec::new();
+    let res = JitProgram::with_config(program, &CodegenConfig::default())
+        .unwrap()
+        .run(
+            &mut io_utils::io_triple(ReadIter::empty(), &mut stdout, empty())
+                .with_eoi::<EOIPanic>(),
+        );
+
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    assert_eq!(stdout, b"\0");
+}
+
+#[test]
+fn jit_tape_state() {
+    let program = BfIrScope::parse_sl(b"+>++>+++<").unwrap();
+    let state = JitProgram::with_config(program, &CodegenConfig::default())
+        .unwrap()
+        .run(&mut io_utils::void())
+        .unwrap();
+
+    assert_eq!(state.tape[..4], [1, 2, 3, 0]);
+    assert_eq!(state.data_ptr, 1);
+
+    // The data pointer wraps around a host-supplied tape
+    let program = BfIrScope::parse_sl(b"<<+").unwrap();
+    let mut state = JitState::with_len(8);
+    state.data_ptr = 1;
+    JitProgram::with_config(program, &CodegenConfig::default())
+        .unwrap()
+        .run_with_state(&mut io_utils::void(), &mut state)
+        .unwrap();
+
+    assert_eq!(state.tape[7], 1);
+    assert_eq!(state.data_ptr, 7);
+}
+
+#[test]
+fn codegen_config() {
+    let config = CodegenConfig::default()
+        .with_opt_level(CodegenOptLevel::None)
+        .with_setting("enable_alias_analysis", "false");
+    let isa = config.isa().unwrap();
+    assert_eq!(isa.flags().opt_level(), settings::OptLevel::None);
+    assert!(!isa.flags().enable_alias_analysis());
+
+    let program = BfIrScope::parse_sl(b"++++++++[>++++++++<-]>+.").unwrap();
+    let state = JitProgram::with_config(program.clone(), &config)
+        .unwrap()
+        .run(&mut io_utils::void())
+        .unwrap();
+    assert_eq!(state.tape[1], b'A');
+
+    // Other targets can be compiled for, but not run, even with the host's architecture
+    let mut other_os = target_lexicon::Triple::host();
+    other_os.operating_system = target_lexicon::OperatingSystem::None_;
+    let config = CodegenConfig::default().with_triple(other_os);
+    assert!(JitProgram::with_config(program.clone(), &config).is_err());
+    let triple = target_lexicon::triple!("riscv64gc-unknown-linux-gnu");
+    let config = CodegenConfig::default().with_triple(triple.clone());
+    assert_eq!(config.isa().unwrap().triple(), &triple);
+    assert!(JitProgram::with_config(program.clone(), &config).is_err());
+    let mut backend = JitBackend {
+        config,
+        ..Default::default()
+    };
+    assert!(backend.run(program, io_utils::void()).is_err());
+}
+
 #[test]
 fn _run_tests() {
     run_tests()